@@ -1,5 +1,12 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{BufRead, Read, Write};
+use std::rc::Rc;
 
+#[derive(PartialEq, Debug)]
 enum Command {
     SetVar(String, Value),
     GetVar(String),
@@ -7,300 +14,8099 @@ enum Command {
     Push(Value),
     Pop,
     Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Neg,
+    Eq,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    And,
+    Or,
+    Not,
+    Dup,
+    Swap,
+    Over,
+    Rot,
+    Drop,
+    Clear,
+    Depth,
+    Print,
+    DelVar(String),
+    MakeList(usize),
+    Index,
+    Len,
+    Append,
+    Upper,
+    Lower,
+    Substr,
+    Split,
+    Peek,
+    TypeOf,
+    ToInt,
+    ToStr,
+    Label(String),
+    Jmp(String),
+    JmpIf(String),
+    If(Vec<Command>, Vec<Command>),
+    While(Vec<Command>),
+    Def(String, Rc<Vec<Command>>),
+    Call(String),
+    Pick(usize),
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+    BitNot,
+    Sum,
+    #[cfg(feature = "serde")]
+    Dump(String),
+    #[cfg(feature = "serde")]
+    Load(String),
+    Sort,
+    Reverse,
+    Range,
+    Contains,
+    Scope,
+    EndScope,
+    SetConst(String, Value),
+    ListVars,
+    HasVar(String),
+    SwapVar(String, String),
+    Inc(String),
+    Dec(String),
+    Min,
+    Max,
+    Pow,
+    Abs,
+    Trim,
+    Replace,
+    Join,
+    Nip,
+    Tuck,
+    TwoDup,
+    Caesar(i64),
+    Format(usize),
+    Assert,
+    AssertEq,
+    Concat,
+    Repeat(usize, Vec<Command>),
+    Count,
+    Slice,
+}
+
+// Writes a command the way it would appear in source. Blocks only show the
+// keyword that opens them.
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::SetVar(name, value) => write!(f, "set {} {}", name, literal(value)),
+            Command::SetConst(name, value) => write!(f, "const {} {}", name, literal(value)),
+            Command::GetVar(name) => write!(f, "get {}", name),
+            Command::PushVar(name) => write!(f, "pushvar {}", name),
+            Command::HasVar(name) => write!(f, "has {}", name),
+            Command::SwapVar(a, b) => write!(f, "swapvar {} {}", a, b),
+            Command::Inc(name) => write!(f, "inc {}", name),
+            Command::Dec(name) => write!(f, "dec {}", name),
+            Command::Push(value) => write!(f, "push {}", literal(value)),
+            Command::Pop => write!(f, "pop"),
+            Command::Add => write!(f, "add"),
+            Command::Sub => write!(f, "sub"),
+            Command::Mul => write!(f, "mul"),
+            Command::Div => write!(f, "div"),
+            Command::Mod => write!(f, "mod"),
+            Command::Pow => write!(f, "pow"),
+            Command::Abs => write!(f, "abs"),
+            Command::Neg => write!(f, "neg"),
+            Command::BitAnd => write!(f, "band"),
+            Command::BitOr => write!(f, "bor"),
+            Command::BitXor => write!(f, "bxor"),
+            Command::Shl => write!(f, "shl"),
+            Command::Shr => write!(f, "shr"),
+            Command::BitNot => write!(f, "bnot"),
+            Command::Sum => write!(f, "sum"),
+            Command::Sort => write!(f, "sort"),
+            Command::Reverse => write!(f, "reverse"),
+            Command::Range => write!(f, "range"),
+            Command::Contains => write!(f, "contains"),
+            Command::Min => write!(f, "min"),
+            Command::Max => write!(f, "max"),
+            Command::Scope => write!(f, "scope"),
+            Command::EndScope => write!(f, "endscope"),
+            Command::ListVars => write!(f, "vars"),
+            Command::Eq => write!(f, "eq"),
+            Command::Lt => write!(f, "lt"),
+            Command::Gt => write!(f, "gt"),
+            Command::Le => write!(f, "le"),
+            Command::Ge => write!(f, "ge"),
+            Command::And => write!(f, "and"),
+            Command::Or => write!(f, "or"),
+            Command::Not => write!(f, "not"),
+            Command::Dup => write!(f, "dup"),
+            Command::Swap => write!(f, "swap"),
+            Command::Over => write!(f, "over"),
+            Command::Nip => write!(f, "nip"),
+            Command::Tuck => write!(f, "tuck"),
+            Command::TwoDup => write!(f, "2dup"),
+            Command::Rot => write!(f, "rot"),
+            Command::Drop => write!(f, "drop"),
+            Command::Clear => write!(f, "clear"),
+            Command::Depth => write!(f, "depth"),
+            Command::Print => write!(f, "print"),
+            Command::Index => write!(f, "index"),
+            Command::Len => write!(f, "len"),
+            Command::Append => write!(f, "append"),
+            Command::Upper => write!(f, "upper"),
+            Command::Lower => write!(f, "lower"),
+            Command::Trim => write!(f, "trim"),
+            Command::Replace => write!(f, "replace"),
+            Command::Join => write!(f, "join"),
+            Command::Substr => write!(f, "substr"),
+            Command::Split => write!(f, "split"),
+            Command::Peek => write!(f, "peek"),
+            Command::TypeOf => write!(f, "typeof"),
+            Command::ToInt => write!(f, "toint"),
+            Command::ToStr => write!(f, "tostr"),
+            Command::DelVar(name) => write!(f, "del {}", name),
+            Command::MakeList(count) => write!(f, "makelist {}", count),
+            Command::Pick(depth) => write!(f, "pick {}", depth),
+            Command::Caesar(shift) => write!(f, "caesar {}", shift),
+            Command::Format(count) => write!(f, "format {}", count),
+            Command::Assert => write!(f, "assert"),
+            Command::AssertEq => write!(f, "assert_eq"),
+            Command::Concat => write!(f, "concat"),
+            Command::Count => write!(f, "count"),
+            Command::Slice => write!(f, "slice"),
+            Command::Label(name) => write!(f, "label {}", name),
+            Command::Jmp(name) => write!(f, "jmp {}", name),
+            Command::JmpIf(name) => write!(f, "jmpif {}", name),
+            Command::If(..) => write!(f, "if"),
+            Command::While(_) => write!(f, "while"),
+            Command::Def(name, _) => write!(f, "def {}", name),
+            Command::Repeat(count, _) => write!(f, "repeat {}", count),
+            Command::Call(name) => write!(f, "call {}", name),
+            #[cfg(feature = "serde")]
+            Command::Dump(path) => {
+                write!(f, "dump {}", literal(&Value::String(path.as_str().into())))
+            }
+            #[cfg(feature = "serde")]
+            Command::Load(path) => {
+                write!(f, "load {}", literal(&Value::String(path.as_str().into())))
+            }
+        }
+    }
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(untagged))]
 enum Value {
     Nothing,
     Int(i64),
-    String(String),
+    Float(f64),
+    Bool(bool),
+    // Strings and lists are shared rather than copied when pushed from a
+    // variable; commands that change them copy them first if needed
+    String(Rc<str>),
+    List(Rc<Vec<Value>>),
+}
+
+#[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+enum Type {
+    Int,
+    Float,
+    Bool,
+    String,
+    List,
+    Nothing,
+    // Only known at runtime, such as an element taken out of a list
+    Any,
+}
+
+// The user-facing rendering of a value: strings have no quotes and
+// nothing is empty
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Nothing => Ok(()),
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::String(s) => write!(f, "{}", s),
+            Value::List(items) => {
+                write!(f, "[")?;
+                for (idx, item) in items.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+// Writes a value the way it would be written as a literal in source
+fn literal(value: &Value) -> String {
+    match value {
+        Value::Nothing => "none".into(),
+        Value::Float(x) if x.is_nan() => "nan".into(),
+        Value::Float(x) => {
+            // Display never uses an exponent, but whole numbers need a
+            // decimal point added to read back as a float
+            let text = x.to_string();
+            if text.contains('.') || x.is_infinite() {
+                text
+            } else {
+                text + ".0"
+            }
+        }
+        Value::String(s) => {
+            let mut output = String::from("\"");
+            for c in s.chars() {
+                match c {
+                    '"' => output.push_str("\\\""),
+                    '\\' => output.push_str("\\\\"),
+                    '\n' => output.push_str("\\n"),
+                    '\t' => output.push_str("\\t"),
+                    c => output.push(c),
+                }
+            }
+            output.push('"');
+            output
+        }
+        Value::List(items) => {
+            let items: Vec<_> = items.iter().map(literal).collect();
+            format!("[{}]", items.join(", "))
+        }
+        _ => value.to_string(),
+    }
+}
+
+impl Type {
+    // The one place that maps values to their types
+    fn of(value: &Value) -> Type {
+        match value {
+            Value::Nothing => Type::Nothing,
+            Value::Int(_) => Type::Int,
+            Value::Float(_) => Type::Float,
+            Value::Bool(_) => Type::Bool,
+            Value::String(_) => Type::String,
+            Value::List(_) => Type::List,
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Type::Int => "int",
+            Type::Float => "float",
+            Type::Bool => "bool",
+            Type::String => "string",
+            Type::List => "list",
+            Type::Any => "any",
+            Type::Nothing => "nothing",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Where in the source something is, counting both from 1
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Span {
+    line: usize,
+    col: usize,
+}
+
+#[derive(Debug)]
+enum EngineError {
+    MismatchNumParams,
+    MismatchType,
+    UnknownCommand(String),
+    MissingVariable(String),
+    EmptyStack,
+    DivideByZero,
+    BadEscape(char),
+    AtLine {
+        span: Span,
+        source: Box<EngineError>,
+    },
+    // A command that failed while running, by its position in its block
+    AtCommand {
+        index: usize,
+        command: String,
+        source: Box<EngineError>,
+    },
+    TypeMismatch {
+        expected: Type,
+        found: Type,
+    },
+    IndexOutOfBounds,
+    Overflow,
+    UnknownLabel(String),
+    UnbalancedBlock,
+    StepLimitExceeded,
+    RecursionLimit,
+    StackOverflow,
+    InvalidShift,
+    Io(String),
+    ConstReassignment(String),
+    BadBytecode,
+    BadFormat,
+    // The two values that should have been equal
+    AssertionFailed {
+        left: Value,
+        right: Value,
+    },
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineError::MismatchNumParams => write!(f, "wrong number of parameters"),
+            EngineError::MismatchType => write!(f, "mismatched types"),
+            EngineError::UnknownCommand(name) => write!(f, "unknown command: {}", name),
+            EngineError::MissingVariable(name) => write!(f, "missing variable: {}", name),
+            EngineError::UnknownLabel(name) => write!(f, "unknown label: {}", name),
+            EngineError::UnbalancedBlock => write!(f, "unbalanced block"),
+            EngineError::StepLimitExceeded => write!(f, "loop ran too many times"),
+            EngineError::RecursionLimit => write!(f, "too many nested calls"),
+            EngineError::StackOverflow => write!(f, "stack overflow"),
+            EngineError::InvalidShift => write!(f, "shift amount must be between 0 and 63"),
+            EngineError::Io(message) => write!(f, "io error: {}", message),
+            EngineError::ConstReassignment(name) => write!(f, "cannot reassign constant: {}", name),
+            EngineError::BadBytecode => write!(f, "invalid bytecode"),
+            EngineError::BadFormat => write!(f, "format placeholder has no matching argument"),
+            EngineError::EmptyStack => write!(f, "not enough values on the stack"),
+            EngineError::DivideByZero => write!(f, "division by zero"),
+            EngineError::Overflow => write!(f, "integer overflow"),
+            EngineError::IndexOutOfBounds => write!(f, "index out of bounds"),
+            EngineError::BadEscape(c) => write!(f, "invalid escape sequence: \\{}", c),
+            EngineError::AtLine { span, source } => {
+                write!(f, "line {}, column {}: {}", span.line, span.col, source)
+            }
+            EngineError::AtCommand {
+                index,
+                command,
+                source,
+            } => write!(f, "command {} ({}): {}", index, command, source),
+            EngineError::AssertionFailed { left, right } => {
+                write!(
+                    f,
+                    "assertion failed: {} != {}",
+                    literal(left),
+                    literal(right)
+                )
+            }
+            EngineError::TypeMismatch { expected, found } => {
+                write!(f, "type mismatch: expected {}, found {}", expected, found)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EngineError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EngineError::AtLine { source, .. } | EngineError::AtCommand { source, .. } => {
+                Some(source.as_ref())
+            }
+            _ => None,
+        }
+    }
+}
+
+impl EngineError {
+    // The error underneath any line or command it was wrapped with
+    #[allow(dead_code)]
+    fn root(self) -> EngineError {
+        match self {
+            EngineError::AtLine { source, .. } | EngineError::AtCommand { source, .. } => {
+                source.root()
+            }
+            err => err,
+        }
+    }
+}
+
+// The parts of an evaluator that `dump` and `load` save and restore
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct State {
+    vars: BTreeMap<String, Value>,
+    stack: Vec<Value>,
+}
+
+const DEFAULT_MAX_ITERATIONS: usize = 1_000_000;
+const DEFAULT_MAX_DEPTH: usize = 100;
+// The longest string `mul` will build, so a huge count fails instead of
+// exhausting memory
+const MAX_STRING_LEN: usize = 1 << 30;
+// Likewise, the most items `range` will build
+const MAX_RANGE_LEN: i64 = 1 << 24;
+
+// Cloning takes a snapshot of the variables, stack and functions to try
+// things on. The clone still writes to the same place as the original.
+#[derive(Clone)]
+struct Evaluator {
+    // One frame of variables per open scope, innermost last. The first
+    // frame holds the global variables and is never removed. Frames are
+    // ordered by name so that listing or dumping them is deterministic.
+    vars: Vec<BTreeMap<String, Value>>,
+    // Names of variables that were defined with `const`, one set for each
+    // frame in `vars`
+    consts: Vec<HashSet<String>>,
+    stack: Vec<Value>,
+    writer: Rc<RefCell<dyn Write>>,
+    // How many times a single loop may run before it's treated as a runaway
+    max_iterations: usize,
+    functions: HashMap<String, Rc<Vec<Command>>>,
+    // How many calls deep we currently are, and how deep we may go
+    depth: usize,
+    max_depth: usize,
+    // Logs each command and the stack after it to the writer
+    trace: bool,
+    // How many commands have run, across every call to evaluate
+    steps: u64,
+    // The most values the stack may hold, if limited
+    max_stack: Option<usize>,
 }
 
-#[derive(Clone, PartialEq, Debug)]
-enum Type {
-    Int,
-    String,
-    Nothing,
-}
+impl Evaluator {
+    fn new() -> Evaluator {
+        Self::with_writer(Rc::new(RefCell::new(std::io::stdout())))
+    }
+
+    fn with_writer(writer: Rc<RefCell<dyn Write>>) -> Evaluator {
+        Self {
+            vars: vec![BTreeMap::new()],
+            consts: vec![HashSet::new()],
+            stack: vec![],
+            writer,
+            max_iterations: DEFAULT_MAX_ITERATIONS,
+            functions: HashMap::new(),
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            trace: false,
+            steps: 0,
+            max_stack: None,
+        }
+    }
+
+    #[allow(dead_code)]
+    fn with_limits(max_stack: usize) -> Evaluator {
+        let mut evaluator = Self::new();
+        evaluator.max_stack = Some(max_stack);
+        evaluator
+    }
+
+    // Looks in the innermost scope first, then outward
+    fn get_var(&self, name: &str) -> Option<&Value> {
+        self.vars.iter().rev().find_map(|frame| frame.get(name))
+    }
+
+    fn set_var(&mut self, name: &str, value: Value) {
+        let frame = self.vars.last_mut().expect("global frame is never removed");
+        frame.insert(name.into(), value);
+    }
+
+    fn var_mut(&mut self, name: &str) -> Option<&mut Value> {
+        self.vars
+            .iter_mut()
+            .rev()
+            .find_map(|frame| frame.get_mut(name))
+    }
+
+    fn del_var(&mut self, name: &str) -> Option<Value> {
+        self.vars
+            .iter_mut()
+            .zip(self.consts.iter_mut())
+            .rev()
+            .find_map(|(frame, consts)| {
+                let value = frame.remove(name)?;
+                // A deleted constant frees up its name
+                consts.remove(name);
+                Some(value)
+            })
+    }
+
+    // Whether the variable `name` resolves to was defined with `const`
+    fn is_const(&self, name: &str) -> bool {
+        self.vars
+            .iter()
+            .zip(&self.consts)
+            .rev()
+            .find(|(frame, _)| frame.contains_key(name))
+            .is_some_and(|(_, consts)| consts.contains(name))
+    }
+
+    // Every variable that's visible from the innermost scope
+    fn all_vars(&self) -> BTreeMap<String, Value> {
+        let mut vars = BTreeMap::new();
+        for frame in &self.vars {
+            vars.extend(
+                frame
+                    .iter()
+                    .map(|(name, value)| (name.clone(), value.clone())),
+            );
+        }
+        vars
+    }
+
+    // Clears all variables, functions and the stack so the evaluator can be reused
+    #[allow(dead_code)]
+    fn reset(&mut self) {
+        self.vars = vec![BTreeMap::new()];
+        self.consts = vec![HashSet::new()];
+        self.stack.clear();
+        self.functions.clear();
+        self.depth = 0;
+        self.steps = 0;
+    }
+
+    #[allow(dead_code)]
+    fn step_count(&self) -> u64 {
+        self.steps
+    }
+
+    fn push(&mut self, value: Value) -> Result<(), EngineError> {
+        self.check_room(1)?;
+        self.stack.push(value);
+        Ok(())
+    }
+
+    // Fails if pushing `count` more values would go past `max_stack`, so
+    // commands that push several values can check before changing anything
+    fn check_room(&self, count: usize) -> Result<(), EngineError> {
+        if let Some(max_stack) = self.max_stack {
+            if self.stack.len().saturating_add(count) > max_stack {
+                return Err(EngineError::StackOverflow);
+            }
+        }
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<Value, EngineError> {
+        let result = self.stack.pop();
+        match result {
+            Some(v) => Ok(v),
+            None => Err(EngineError::EmptyStack),
+        }
+    }
+
+    fn peek(&self) -> Result<&Value, EngineError> {
+        match self.stack.last() {
+            Some(v) => Ok(v),
+            None => Err(EngineError::EmptyStack),
+        }
+    }
+
+    fn add(&self, lhs: Value, rhs: Value) -> Result<Value, EngineError> {
+        match (lhs, rhs) {
+            (Value::Int(i1), Value::Int(i2)) => checked(i1.checked_add(i2)),
+            (Value::String(s1), Value::String(s2)) => {
+                Ok(Value::String(format!("{}{}", s1, s2).into()))
+            }
+            (lhs, rhs) => match float_operands(&lhs, &rhs) {
+                Some((f1, f2)) => Ok(Value::Float(f1 + f2)),
+                None => Err(add_mismatch(Type::of(&lhs), Type::of(&rhs))),
+            },
+        }
+    }
+
+    fn subtract(&self, lhs: Value, rhs: Value) -> Result<Value, EngineError> {
+        match (lhs, rhs) {
+            (Value::Int(i1), Value::Int(i2)) => checked(i1.checked_sub(i2)),
+            (lhs, rhs) => match float_operands(&lhs, &rhs) {
+                Some((f1, f2)) => Ok(Value::Float(f1 - f2)),
+                None => Err(EngineError::MismatchType),
+            },
+        }
+    }
+
+    fn multiply(&self, lhs: Value, rhs: Value) -> Result<Value, EngineError> {
+        match (lhs, rhs) {
+            (Value::Int(i1), Value::Int(i2)) => checked(i1.checked_mul(i2)),
+            (Value::String(s), Value::Int(count)) | (Value::Int(count), Value::String(s)) => {
+                // A negative count repeats the string zero times
+                let count = usize::try_from(count.max(0)).map_err(|_| EngineError::Overflow)?;
+                match s.len().checked_mul(count) {
+                    Some(total) if total <= MAX_STRING_LEN => {}
+                    _ => return Err(EngineError::Overflow),
+                }
+                Ok(Value::String(s.repeat(count).into()))
+            }
+            (lhs, rhs) => match float_operands(&lhs, &rhs) {
+                Some((f1, f2)) => Ok(Value::Float(f1 * f2)),
+                None => Err(EngineError::MismatchType),
+            },
+        }
+    }
+
+    fn divide(&self, lhs: Value, rhs: Value) -> Result<Value, EngineError> {
+        match (lhs, rhs) {
+            (Value::Int(_), Value::Int(0)) => Err(EngineError::DivideByZero),
+            (Value::Int(i1), Value::Int(i2)) => checked(i1.checked_div(i2)),
+            (lhs, rhs) => match float_operands(&lhs, &rhs) {
+                Some((_, 0.0)) => Err(EngineError::DivideByZero),
+                Some((f1, f2)) => Ok(Value::Float(f1 / f2)),
+                None => Err(EngineError::MismatchType),
+            },
+        }
+    }
+
+    fn modulo(&self, lhs: Value, rhs: Value) -> Result<Value, EngineError> {
+        match (lhs, rhs) {
+            (Value::Int(_), Value::Int(0)) => Err(EngineError::DivideByZero),
+            (Value::Int(i1), Value::Int(i2)) => checked(i1.checked_rem(i2)),
+            _ => Err(EngineError::MismatchType),
+        }
+    }
+
+    fn power(&self, base: Value, exp: Value) -> Result<Value, EngineError> {
+        match (base, exp) {
+            (Value::Int(_), Value::Int(exp)) if exp < 0 => Err(EngineError::MismatchType),
+            (Value::Int(base), Value::Int(exp)) => match u32::try_from(exp) {
+                Ok(exp) => checked(base.checked_pow(exp)),
+                // Only these bases stay in range for such large exponents
+                Err(_) => match base {
+                    0 | 1 => Ok(Value::Int(base)),
+                    -1 => Ok(Value::Int(if exp % 2 == 0 { 1 } else { -1 })),
+                    _ => Err(EngineError::Overflow),
+                },
+            },
+            _ => Err(EngineError::MismatchType),
+        }
+    }
+
+    fn bitwise(&self, command: &Command, lhs: Value, rhs: Value) -> Result<Value, EngineError> {
+        let (i1, i2) = match (lhs, rhs) {
+            (Value::Int(i1), Value::Int(i2)) => (i1, i2),
+            _ => return Err(EngineError::MismatchType),
+        };
+
+        let result = match command {
+            Command::BitAnd => i1 & i2,
+            Command::BitOr => i1 | i2,
+            Command::BitXor => i1 ^ i2,
+            Command::Shl | Command::Shr => {
+                let shift = match u32::try_from(i2) {
+                    Ok(shift) if shift < 64 => shift,
+                    _ => return Err(EngineError::InvalidShift),
+                };
+                if let Command::Shl = command {
+                    i1 << shift
+                } else {
+                    i1 >> shift
+                }
+            }
+            _ => unreachable!("not a bitwise command"),
+        };
+        Ok(Value::Int(result))
+    }
+
+    fn negate(&self, value: Value) -> Result<Value, EngineError> {
+        match value {
+            Value::Int(i) => checked(i.checked_neg()),
+            Value::Float(f) => Ok(Value::Float(-f)),
+            _ => Err(EngineError::MismatchType),
+        }
+    }
+
+    // Takes `len` characters starting at character `start`
+    fn substring(&self, s: &str, start: i64, len: i64) -> Result<Value, EngineError> {
+        let start = usize::try_from(start).map_err(|_| EngineError::IndexOutOfBounds)?;
+        let len = usize::try_from(len).map_err(|_| EngineError::IndexOutOfBounds)?;
+
+        match start.checked_add(len) {
+            Some(end) if end <= s.chars().count() => {}
+            _ => return Err(EngineError::IndexOutOfBounds),
+        }
+
+        let result: String = s.chars().skip(start).take(len).collect();
+        Ok(Value::String(result.into()))
+    }
+
+    #[cfg(feature = "serde")]
+    fn dump(&self, path: &str) -> Result<(), EngineError> {
+        let state = State {
+            vars: self.all_vars(),
+            stack: self.stack.clone(),
+        };
+        let json = serde_json::to_string(&state).map_err(|err| EngineError::Io(err.to_string()))?;
+        std::fs::write(path, json).map_err(|err| EngineError::Io(err.to_string()))
+    }
+
+    // Replaces the variables and stack with ones saved by `dump`
+    #[cfg(feature = "serde")]
+    fn load(&mut self, path: &str) -> Result<(), EngineError> {
+        let json = std::fs::read_to_string(path).map_err(|err| EngineError::Io(err.to_string()))?;
+        let state: State =
+            serde_json::from_str(&json).map_err(|err| EngineError::Io(err.to_string()))?;
+
+        if let Some(max_stack) = self.max_stack {
+            if state.stack.len() > max_stack {
+                return Err(EngineError::StackOverflow);
+            }
+        }
+        self.vars = vec![state.vars];
+        self.consts = vec![HashSet::new()];
+        self.stack = state.stack;
+        Ok(())
+    }
+
+    fn evaluate(&mut self, commands: &[Command]) -> Result<Value, EngineError> {
+        let mut output = Ok(Value::Nothing);
+        self.run_block(commands, &mut output)?;
+        output
+    }
+
+    // Runs a list of commands, updating `output` as a result is produced.
+    // Errors are wrapped with the command that caused them, so a failure in
+    // a nested block or function carries the whole chain of commands.
+    fn run_block(
+        &mut self,
+        commands: &[Command],
+        output: &mut Result<Value, EngineError>,
+    ) -> Result<(), EngineError> {
+        let mut pc = 0;
+        self.run_from(commands, &mut pc, output).map_err(|source| {
+            // `pc` has already moved past the command that failed
+            EngineError::AtCommand {
+                index: pc - 1,
+                command: commands[pc - 1].to_string(),
+                source: Box::new(source),
+            }
+        })
+    }
+
+    // Labels are local to the block they're defined in
+    fn run_from(
+        &mut self,
+        commands: &[Command],
+        pc: &mut usize,
+        output: &mut Result<Value, EngineError>,
+    ) -> Result<(), EngineError> {
+        let labels = find_labels(commands);
+        let mut back_jumps = 0;
+
+        while *pc < commands.len() {
+            let command = &commands[*pc];
+            *pc += 1;
+            self.steps += 1;
+
+            // Commands that run other commands are handled here, so that the
+            // much bigger frame of run_command isn't held while they run
+            match command {
+                Command::Label(_) => {}
+                Command::Jmp(name) => self.jump(&labels, name, pc, &mut back_jumps)?,
+                Command::JmpIf(name) => match self.pop()? {
+                    Value::Bool(true) => self.jump(&labels, name, pc, &mut back_jumps)?,
+                    Value::Bool(false) => {}
+                    _ => return Err(EngineError::MismatchType),
+                },
+                Command::If(then, otherwise) => match self.pop()? {
+                    Value::Bool(true) => self.run_block(then, output)?,
+                    Value::Bool(false) => self.run_block(otherwise, output)?,
+                    _ => return Err(EngineError::MismatchType),
+                },
+                Command::While(body) => {
+                    let mut iterations = 0;
+                    loop {
+                        match self.pop()? {
+                            Value::Bool(true) => {}
+                            Value::Bool(false) => break,
+                            _ => return Err(EngineError::MismatchType),
+                        }
+                        iterations += 1;
+                        if iterations > self.max_iterations {
+                            return Err(EngineError::StepLimitExceeded);
+                        }
+                        self.run_block(body, output)?;
+                    }
+                }
+                Command::Repeat(count, body) => {
+                    for iteration in 0..*count {
+                        if iteration >= self.max_iterations {
+                            return Err(EngineError::StepLimitExceeded);
+                        }
+                        self.run_block(body, output)?;
+                    }
+                }
+                Command::Def(name, body) => {
+                    self.functions.insert(name.clone(), body.clone());
+                }
+                Command::Call(name) => {
+                    let body = match self.functions.get(name) {
+                        Some(body) => body.clone(),
+                        None => return Err(EngineError::UnknownCommand(name.into())),
+                    };
+                    if self.depth >= self.max_depth {
+                        return Err(EngineError::RecursionLimit);
+                    }
+
+                    self.depth += 1;
+                    let result = self.run_block(&body, output);
+                    self.depth -= 1;
+                    result?;
+                }
+                _ => self.run_command(command, output)?,
+            }
+
+            if self.trace {
+                let stack: Vec<_> = self.stack.iter().map(literal).collect();
+                let _ = writeln!(
+                    self.writer.borrow_mut(),
+                    "{} -> [{}]",
+                    command,
+                    stack.join(", ")
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // Jumping backwards makes a loop, so those jumps have the same limit as
+    // the iterations of a while
+    fn jump(
+        &self,
+        labels: &HashMap<String, usize>,
+        name: &str,
+        pc: &mut usize,
+        back_jumps: &mut usize,
+    ) -> Result<(), EngineError> {
+        let target = match labels.get(name) {
+            Some(target) => *target,
+            None => return Err(EngineError::UnknownLabel(name.into())),
+        };
+        if target < *pc {
+            *back_jumps += 1;
+            if *back_jumps > self.max_iterations {
+                return Err(EngineError::StepLimitExceeded);
+            }
+        }
+        *pc = target;
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn run_command(
+        &mut self,
+        command: &Command,
+        output: &mut Result<Value, EngineError>,
+    ) -> Result<(), EngineError> {
+        match command {
+            Command::SetVar(name, value) => {
+                if self.is_const(name) {
+                    return Err(EngineError::ConstReassignment(name.into()));
+                }
+                self.set_var(name, value.clone())
+            }
+            Command::SetConst(name, value) => {
+                if self.is_const(name) {
+                    return Err(EngineError::ConstReassignment(name.into()));
+                }
+                let consts = self
+                    .consts
+                    .last_mut()
+                    .expect("global frame is never removed");
+                consts.insert(name.clone());
+                self.set_var(name, value.clone())
+            }
+            Command::GetVar(name) => match self.get_var(name) {
+                Some(value) => *output = Ok(value.clone()),
+                None => return Err(EngineError::MissingVariable(name.into())),
+            },
+            Command::PushVar(name) => match self.get_var(name) {
+                Some(value) => self.push(value.clone())?,
+                None => return Err(EngineError::MissingVariable(name.into())),
+            },
+            Command::Push(v) => self.push(v.clone())?,
+            Command::Pop => {
+                *output = Ok(self.pop()?);
+            }
+            Command::Add => {
+                let lhs = self.pop()?;
+                let rhs = self.pop()?;
+
+                let result = self.add(lhs, rhs)?;
+                self.push(result)?
+            }
+            Command::Sub => {
+                // Unlike add, the top of the stack is the right-hand side,
+                // so `push 100`, `push 30`, `sub` computes 100 - 30
+                let rhs = self.pop()?;
+                let lhs = self.pop()?;
+
+                let result = self.subtract(lhs, rhs)?;
+                self.push(result)?
+            }
+            Command::Mul => {
+                let rhs = self.pop()?;
+                let lhs = self.pop()?;
+
+                let result = self.multiply(lhs, rhs)?;
+                self.push(result)?
+            }
+            Command::Div => {
+                let rhs = self.pop()?;
+                let lhs = self.pop()?;
+
+                let result = self.divide(lhs, rhs)?;
+                self.push(result)?
+            }
+            Command::Mod => {
+                let rhs = self.pop()?;
+                let lhs = self.pop()?;
+
+                let result = self.modulo(lhs, rhs)?;
+                self.push(result)?
+            }
+            Command::Neg => {
+                let value = self.pop()?;
+
+                let result = self.negate(value)?;
+                self.push(result)?
+            }
+            Command::BitAnd | Command::BitOr | Command::BitXor | Command::Shl | Command::Shr => {
+                let rhs = self.pop()?;
+                let lhs = self.pop()?;
+
+                let result = self.bitwise(command, lhs, rhs)?;
+                self.push(result)?
+            }
+            Command::BitNot => match self.pop()? {
+                Value::Int(i) => self.push(Value::Int(!i))?,
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Sum => {
+                let mut total = Value::Int(0);
+                for value in std::mem::take(&mut self.stack) {
+                    total = match (total, value) {
+                        (Value::Int(i1), Value::Int(i2)) => checked(i1.checked_add(i2))?,
+                        _ => return Err(EngineError::MismatchType),
+                    };
+                }
+                self.push(total)?
+            }
+            Command::Eq => {
+                let rhs = self.pop()?;
+                let lhs = self.pop()?;
+
+                // Values of different kinds are never equal
+                self.push(Value::Bool(lhs == rhs))?
+            }
+            Command::Lt | Command::Gt | Command::Le | Command::Ge => {
+                let rhs = self.pop()?;
+                let lhs = self.pop()?;
+
+                let ordering = compare(&lhs, &rhs)?;
+                let result = match command {
+                    Command::Lt => ordering == Ordering::Less,
+                    Command::Gt => ordering == Ordering::Greater,
+                    Command::Le => ordering != Ordering::Greater,
+                    _ => ordering != Ordering::Less,
+                };
+                self.push(Value::Bool(result))?
+            }
+            Command::And | Command::Or => {
+                // Both operands have already been evaluated onto the stack,
+                // so there's no short-circuiting
+                let rhs = self.pop()?;
+                let lhs = self.pop()?;
+
+                let result = match (command, lhs, rhs) {
+                    (Command::And, Value::Bool(b1), Value::Bool(b2)) => b1 && b2,
+                    (Command::Or, Value::Bool(b1), Value::Bool(b2)) => b1 || b2,
+                    _ => return Err(EngineError::MismatchType),
+                };
+                self.push(Value::Bool(result))?
+            }
+            Command::Not => match self.pop()? {
+                Value::Bool(b) => self.push(Value::Bool(!b))?,
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Dup => {
+                let value = self.peek()?.clone();
+                self.push(value)?
+            }
+            Command::Swap => {
+                let len = self.stack.len();
+                if len < 2 {
+                    return Err(EngineError::EmptyStack);
+                }
+                self.stack.swap(len - 1, len - 2);
+            }
+            Command::Over => {
+                let len = self.stack.len();
+                if len < 2 {
+                    return Err(EngineError::EmptyStack);
+                }
+                self.push(self.stack[len - 2].clone())?;
+            }
+            Command::Pick(depth) => match self
+                .stack
+                .len()
+                .checked_sub(1)
+                .and_then(|top| top.checked_sub(*depth))
+            {
+                Some(idx) => self.push(self.stack[idx].clone())?,
+                None => return Err(EngineError::EmptyStack),
+            },
+            Command::Rot => {
+                // a b c -> b c a
+                let len = self.stack.len();
+                if len < 3 {
+                    return Err(EngineError::EmptyStack);
+                }
+                self.stack[(len - 3)..].rotate_left(1);
+            }
+            Command::Drop => {
+                self.pop()?;
+            }
+            Command::Clear => self.stack.clear(),
+            Command::Depth => self.push(Value::Int(self.stack.len() as i64))?,
+            Command::Print => {
+                let value = self.peek()?;
+                let _ = writeln!(self.writer.borrow_mut(), "{}", value);
+            }
+            Command::DelVar(name) => {
+                if self.del_var(name).is_none() {
+                    return Err(EngineError::MissingVariable(name.into()));
+                }
+            }
+            Command::MakeList(count) => {
+                let len = self.stack.len();
+                if len < *count {
+                    return Err(EngineError::EmptyStack);
+                }
+                let items = self.stack.split_off(len - count);
+                self.push(Value::List(Rc::new(items)))?;
+            }
+            Command::Index => {
+                let index = self.pop()?;
+                let list = self.pop()?;
+
+                match (list, index) {
+                    (Value::List(items), Value::Int(i)) => match usize::try_from(i) {
+                        Ok(i) if i < items.len() => self.push(items[i].clone())?,
+                        _ => return Err(EngineError::IndexOutOfBounds),
+                    },
+                    _ => return Err(EngineError::MismatchType),
+                }
+            }
+            Command::Len => {
+                let len = match self.pop()? {
+                    Value::List(items) => items.len(),
+                    Value::String(s) => s.chars().count(),
+                    _ => return Err(EngineError::MismatchType),
+                };
+                self.push(Value::Int(len as i64))?
+            }
+            Command::Append => {
+                let value = self.pop()?;
+
+                match self.pop()? {
+                    Value::List(mut items) => {
+                        Rc::make_mut(&mut items).push(value);
+                        self.push(Value::List(items))?
+                    }
+                    _ => return Err(EngineError::MismatchType),
+                }
+            }
+            Command::Upper | Command::Lower => match self.pop()? {
+                Value::String(s) => {
+                    let result = match command {
+                        Command::Upper => s.to_uppercase(),
+                        _ => s.to_lowercase(),
+                    };
+                    self.push(Value::String(result.into()))?
+                }
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Substr => {
+                let len = self.pop()?;
+                let start = self.pop()?;
+                let source = self.pop()?;
+
+                let result = match (source, start, len) {
+                    (Value::String(s), Value::Int(start), Value::Int(len)) => {
+                        self.substring(&s, start, len)?
+                    }
+                    _ => return Err(EngineError::MismatchType),
+                };
+                self.push(result)?
+            }
+            Command::Split => {
+                let separator = self.pop()?;
+                let source = self.pop()?;
+
+                let pieces: Vec<Value> = match (source, separator) {
+                    // An empty separator splits into characters
+                    (Value::String(s), Value::String(sep)) if sep.is_empty() => s
+                        .chars()
+                        .map(|c| Value::String(c.to_string().into()))
+                        .collect(),
+                    (Value::String(s), Value::String(sep)) => s
+                        .split(&*sep)
+                        .map(|piece| Value::String(piece.into()))
+                        .collect(),
+                    _ => return Err(EngineError::MismatchType),
+                };
+                self.push(Value::List(Rc::new(pieces)))?
+            }
+            Command::Peek => {
+                *output = Ok(self.peek()?.clone());
+            }
+            Command::TypeOf => {
+                let value = self.pop()?;
+                let name = Type::of(&value).to_string();
+                self.push(Value::String(name.into()))?
+            }
+            Command::ToInt => match self.pop()? {
+                Value::String(s) => {
+                    let result = parse_int(&s)?;
+                    self.push(result)?
+                }
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::ToStr => {
+                let value = self.pop()?;
+                self.push(Value::String(value.to_string().into()))?
+            }
+            #[cfg(feature = "serde")]
+            Command::Dump(path) => self.dump(path)?,
+            #[cfg(feature = "serde")]
+            Command::Load(path) => self.load(path)?,
+            Command::Sort => match self.pop()? {
+                Value::List(mut items) => {
+                    let sortable = items.iter().all(|x| matches!(x, Value::Int(_)))
+                        || items.iter().all(|x| matches!(x, Value::String(_)));
+                    if !sortable {
+                        return Err(EngineError::MismatchType);
+                    }
+
+                    // Every pair compares, since the items are all ints or all strings
+                    Rc::make_mut(&mut items)
+                        .sort_by(|a, b| compare(a, b).unwrap_or(Ordering::Equal));
+                    self.push(Value::List(items))?
+                }
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Reverse => match self.pop()? {
+                Value::List(mut items) => {
+                    Rc::make_mut(&mut items).reverse();
+                    self.push(Value::List(items))?
+                }
+                Value::String(s) => {
+                    let reversed: String = s.chars().rev().collect();
+                    self.push(Value::String(reversed.into()))?
+                }
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Range => {
+                let end = self.pop()?;
+                let start = self.pop()?;
+
+                match (start, end) {
+                    (Value::Int(start), Value::Int(end)) => {
+                        match end.checked_sub(start) {
+                            Some(len) if len <= MAX_RANGE_LEN => {}
+                            _ => return Err(EngineError::Overflow),
+                        }
+                        let items = (start..end).map(Value::Int).collect();
+                        self.push(Value::List(Rc::new(items)))?
+                    }
+                    _ => return Err(EngineError::MismatchType),
+                }
+            }
+            Command::Contains => {
+                let needle = self.pop()?;
+                let haystack = self.pop()?;
+
+                let result = match (haystack, needle) {
+                    (Value::String(s), Value::String(sub)) => s.contains(&*sub),
+                    (Value::List(items), needle) => items.contains(&needle),
+                    _ => return Err(EngineError::MismatchType),
+                };
+                self.push(Value::Bool(result))?
+            }
+            Command::Scope => {
+                self.vars.push(BTreeMap::new());
+                self.consts.push(HashSet::new());
+            }
+            Command::EndScope => {
+                if self.vars.len() == 1 {
+                    return Err(EngineError::UnbalancedBlock);
+                }
+                self.vars.pop();
+                self.consts.pop();
+            }
+            Command::ListVars => {
+                // Already sorted, since the variables are kept in order
+                let names = self
+                    .all_vars()
+                    .into_keys()
+                    .map(|name| Value::String(name.into()));
+                self.push(Value::List(Rc::new(names.collect())))?
+            }
+            Command::HasVar(name) => {
+                let defined = self.get_var(name).is_some();
+                self.push(Value::Bool(defined))?
+            }
+            Command::SwapVar(a, b) => {
+                for name in [a, b] {
+                    if self.is_const(name) {
+                        return Err(EngineError::ConstReassignment(name.into()));
+                    }
+                }
+                let value_a = match self.get_var(a) {
+                    Some(value) => value.clone(),
+                    None => return Err(EngineError::MissingVariable(a.into())),
+                };
+                let value_b = match self.var_mut(b) {
+                    Some(value) => std::mem::replace(value, value_a),
+                    None => return Err(EngineError::MissingVariable(b.into())),
+                };
+                if let Some(value) = self.var_mut(a) {
+                    *value = value_b;
+                }
+            }
+            Command::Inc(name) | Command::Dec(name) => {
+                if self.is_const(name) {
+                    return Err(EngineError::ConstReassignment(name.into()));
+                }
+                let delta = if let Command::Inc(_) = command { 1 } else { -1 };
+                match self.var_mut(name) {
+                    Some(Value::Int(i)) => {
+                        *i = i.checked_add(delta).ok_or(EngineError::Overflow)?
+                    }
+                    Some(_) => return Err(EngineError::MismatchType),
+                    None => return Err(EngineError::MissingVariable(name.into())),
+                }
+            }
+            Command::Min | Command::Max => {
+                let rhs = self.pop()?;
+                let lhs = self.pop()?;
+
+                let ordering = compare(&lhs, &rhs)?;
+                let take_rhs = match command {
+                    Command::Min => ordering == Ordering::Greater,
+                    _ => ordering == Ordering::Less,
+                };
+                self.push(if take_rhs { rhs } else { lhs })?
+            }
+            Command::Pow => {
+                let exp = self.pop()?;
+                let base = self.pop()?;
+
+                let result = self.power(base, exp)?;
+                self.push(result)?
+            }
+            Command::Abs => match self.pop()? {
+                Value::Int(i) => self.push(checked(i.checked_abs())?)?,
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Trim => match self.pop()? {
+                Value::String(s) => self.push(Value::String(s.trim().into()))?,
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Replace => {
+                let replacement = self.pop()?;
+                let pattern = self.pop()?;
+                let source = self.pop()?;
+
+                match (source, pattern, replacement) {
+                    // Replacing an empty pattern would insert between every character
+                    (source @ Value::String(_), Value::String(pattern), Value::String(_))
+                        if pattern.is_empty() =>
+                    {
+                        self.push(source)?
+                    }
+                    (Value::String(s), Value::String(pattern), Value::String(replacement)) => {
+                        let result = s.replace(&*pattern, &replacement);
+                        self.push(Value::String(result.into()))?
+                    }
+                    _ => return Err(EngineError::MismatchType),
+                }
+            }
+            Command::Join => {
+                let separator = self.pop()?;
+
+                match (self.pop()?, separator) {
+                    (Value::List(items), Value::String(separator)) => {
+                        // Nothing displays as an empty string
+                        let pieces: Vec<_> = items.iter().map(|item| item.to_string()).collect();
+                        self.push(Value::String(pieces.join(&separator).into()))?
+                    }
+                    _ => return Err(EngineError::MismatchType),
+                }
+            }
+            Command::Nip => {
+                let len = self.stack.len();
+                if len < 2 {
+                    return Err(EngineError::EmptyStack);
+                }
+                self.stack.remove(len - 2);
+            }
+            Command::Tuck => {
+                // a b -> b a b
+                let len = self.stack.len();
+                if len < 2 {
+                    return Err(EngineError::EmptyStack);
+                }
+                self.check_room(1)?;
+                self.stack.insert(len - 2, self.stack[len - 1].clone());
+            }
+            Command::TwoDup => {
+                let len = self.stack.len();
+                if len < 2 {
+                    return Err(EngineError::EmptyStack);
+                }
+                self.check_room(2)?;
+                self.stack.extend_from_within((len - 2)..);
+            }
+            Command::Caesar(shift) => match self.pop()? {
+                Value::String(s) => {
+                    let result: String = s.chars().map(|c| caesar(c, *shift)).collect();
+                    self.push(Value::String(result.into()))?
+                }
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Format(count) => {
+                // Nothing is taken off the stack until the format succeeds
+                let len = self.stack.len();
+                if len <= *count {
+                    return Err(EngineError::EmptyStack);
+                }
+                let result = match &self.stack[len - 1] {
+                    Value::String(template) => {
+                        format_template(template, &self.stack[(len - 1 - count)..(len - 1)])?
+                    }
+                    _ => return Err(EngineError::MismatchType),
+                };
+                self.stack.truncate(len - 1 - count);
+                self.push(Value::String(result.into()))?;
+            }
+            Command::Assert => match self.pop()? {
+                Value::Bool(true) => {}
+                value => {
+                    return Err(EngineError::AssertionFailed {
+                        left: value,
+                        right: Value::Bool(true),
+                    })
+                }
+            },
+            Command::AssertEq => {
+                let right = self.pop()?;
+                let left = self.pop()?;
+                if left != right {
+                    return Err(EngineError::AssertionFailed { left, right });
+                }
+            }
+            Command::Concat => {
+                let rhs = self.pop()?;
+                let lhs = self.pop()?;
+                self.push(Value::String(format!("{}{}", lhs, rhs).into()))?;
+            }
+            Command::Count => {
+                let needle = self.pop()?;
+                let items = match self.pop()? {
+                    Value::List(items) => items,
+                    _ => return Err(EngineError::MismatchType),
+                };
+                let count = items.iter().filter(|item| **item == needle).count();
+                self.push(Value::Int(count as i64))?;
+            }
+            Command::Slice => {
+                let end = self.pop()?;
+                let start = self.pop()?;
+                let list = self.pop()?;
+
+                let (items, start, end) = match (list, start, end) {
+                    (Value::List(items), Value::Int(start), Value::Int(end)) => (items, start, end),
+                    _ => return Err(EngineError::MismatchType),
+                };
+                let start = usize::try_from(start).map_err(|_| EngineError::IndexOutOfBounds)?;
+                let end = usize::try_from(end).map_err(|_| EngineError::IndexOutOfBounds)?;
+                match items.get(start..end) {
+                    Some(slice) => self.push(Value::List(Rc::new(slice.to_vec())))?,
+                    None => return Err(EngineError::IndexOutOfBounds),
+                }
+            }
+            Command::Label(_)
+            | Command::Jmp(_)
+            | Command::JmpIf(_)
+            | Command::If(..)
+            | Command::While(_)
+            | Command::Repeat(..)
+            | Command::Def(..)
+            | Command::Call(_) => unreachable!("handled by run_block"),
+        }
+        Ok(())
+    }
+
+    // Like evaluate, but returns everything left on the stack afterwards
+    #[allow(dead_code)]
+    fn evaluate_stack(&mut self, commands: &[Command]) -> Result<Vec<Value>, EngineError> {
+        self.evaluate(commands)?;
+        Ok(self.stack.clone())
+    }
+}
+
+// Turns the result of a checked integer operation into a value
+fn checked(result: Option<i64>) -> Result<Value, EngineError> {
+    match result {
+        Some(x) => Ok(Value::Int(x)),
+        None => Err(EngineError::Overflow),
+    }
+}
+
+// Returns both operands as floats when at least one of them is a float,
+// coercing an int operand so that mixed arithmetic works
+fn float_operands(lhs: &Value, rhs: &Value) -> Option<(f64, f64)> {
+    match (lhs, rhs) {
+        (Value::Float(f1), Value::Float(f2)) => Some((*f1, *f2)),
+        (Value::Int(i1), Value::Float(f2)) => Some((*i1 as f64, *f2)),
+        (Value::Float(f1), Value::Int(i2)) => Some((*f1, *i2 as f64)),
+        _ => None,
+    }
+}
+
+// Orders two values of the same kind: numbers numerically and strings
+// lexicographically. Anything else can't be ordered.
+fn compare(lhs: &Value, rhs: &Value) -> Result<Ordering, EngineError> {
+    match (lhs, rhs) {
+        (Value::Int(i1), Value::Int(i2)) => Ok(i1.cmp(i2)),
+        (Value::String(s1), Value::String(s2)) => Ok(s1.cmp(s2)),
+        (lhs, rhs) => match float_operands(lhs, rhs) {
+            Some((f1, f2)) => f1.partial_cmp(&f2).ok_or(EngineError::MismatchType),
+            None => Err(EngineError::MismatchType),
+        },
+    }
+}
+
+// Blames the right-hand side of an add unless the left-hand side can't be
+// added to anything
+fn add_mismatch(lhs: Type, rhs: Type) -> EngineError {
+    let (expected, found) = match lhs {
+        Type::Int | Type::Float | Type::String => (lhs, rhs),
+        _ => (Type::Int, lhs),
+    };
+    EngineError::TypeMismatch { expected, found }
+}
+
+// The type a value has when it may have come from either of two places
+fn join(lhs: Type, rhs: Type) -> Type {
+    if lhs == rhs {
+        lhs
+    } else {
+        Type::Any
+    }
+}
+
+// The type of an arithmetic result on two numbers, where an int mixed
+// with a float gives a float
+fn numeric_type(lhs: &Type, rhs: &Type) -> Option<Type> {
+    match (lhs, rhs) {
+        (Type::Int, Type::Int) => Some(Type::Int),
+        (Type::Float, Type::Float) | (Type::Int, Type::Float) | (Type::Float, Type::Int) => {
+            Some(Type::Float)
+        }
+        (Type::Any, Type::Int | Type::Float | Type::Any) | (Type::Int | Type::Float, Type::Any) => {
+            Some(Type::Any)
+        }
+        _ => None,
+    }
+}
+
+// Maps each label to the position of the command after it. If a label is
+// defined more than once, the first one wins.
+fn find_labels(commands: &[Command]) -> HashMap<String, usize> {
+    let mut labels = HashMap::new();
+
+    for (idx, command) in commands.iter().enumerate() {
+        if let Command::Label(name) = command {
+            labels.entry(name.clone()).or_insert(idx + 1);
+        }
+    }
+
+    labels
+}
+
+// Shifts an ASCII letter along the alphabet, wrapping around at the end
+fn caesar(c: char, shift: i64) -> char {
+    let base = match c {
+        'a'..='z' => b'a',
+        'A'..='Z' => b'A',
+        _ => return c,
+    };
+    let offset = (i64::from(c as u8 - base) + shift.rem_euclid(26)) % 26;
+    (base + offset as u8) as char
+}
+
+// Replaces each {N} in the template with the Nth argument. Braces that
+// don't hold a number are left alone.
+fn format_template(template: &str, args: &[Value]) -> Result<String, EngineError> {
+    let mut output = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let index = rest
+            .find('}')
+            .and_then(|end| rest[1..end].parse::<usize>().ok().map(|index| (index, end)));
+        match index {
+            Some((index, end)) => {
+                let arg = args.get(index).ok_or(EngineError::BadFormat)?;
+                output.push_str(&arg.to_string());
+                rest = &rest[end + 1..];
+            }
+            None => {
+                output.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+// Folds arithmetic on constant ints into a single push. Only operations that
+// are sure to succeed are folded, so errors still happen at runtime. This is
+// opt-in for embedders: traces, step counts and error positions all follow
+// the folded program rather than the source.
+#[allow(dead_code)]
+fn optimize(commands: Vec<Command>) -> Vec<Command> {
+    let mut output: Vec<Command> = vec![];
+
+    for command in commands {
+        let command = match command {
+            Command::If(then, otherwise) => Command::If(optimize(then), optimize(otherwise)),
+            Command::While(body) => Command::While(optimize(body)),
+            Command::Repeat(count, body) => Command::Repeat(count, optimize(body)),
+            Command::Def(name, body) => match Rc::try_unwrap(body) {
+                Ok(body) => Command::Def(name, Rc::new(optimize(body))),
+                Err(body) => Command::Def(name, body),
+            },
+            command => command,
+        };
+
+        let len = output.len();
+        let folded = match (&output[len.saturating_sub(2)..], &command) {
+            ([Command::Push(Value::Int(lhs)), Command::Push(Value::Int(rhs))], _) => {
+                fold_binary(&command, *lhs, *rhs).map(|value| (2, value))
+            }
+            ([.., Command::Push(Value::Int(i))], Command::Neg) => {
+                i.checked_neg().map(|i| (1, Value::Int(i)))
+            }
+            ([.., Command::Push(Value::Int(i))], Command::BitNot) => Some((1, Value::Int(!i))),
+            _ => None,
+        };
+
+        match folded {
+            Some((consumed, value)) => {
+                output.truncate(len - consumed);
+                output.push(Command::Push(value));
+            }
+            None => output.push(command),
+        }
+    }
+
+    output
+}
+
+// The result of a command on two constant ints, if it can't fail
+fn fold_binary(command: &Command, lhs: i64, rhs: i64) -> Option<Value> {
+    let shift = u32::try_from(rhs).ok().filter(|shift| *shift < 64);
+
+    match command {
+        Command::Add => lhs.checked_add(rhs).map(Value::Int),
+        Command::Sub => lhs.checked_sub(rhs).map(Value::Int),
+        Command::Mul => lhs.checked_mul(rhs).map(Value::Int),
+        Command::Div => lhs.checked_div(rhs).map(Value::Int),
+        Command::Mod => lhs.checked_rem(rhs).map(Value::Int),
+        Command::BitAnd => Some(Value::Int(lhs & rhs)),
+        Command::BitOr => Some(Value::Int(lhs | rhs)),
+        Command::BitXor => Some(Value::Int(lhs ^ rhs)),
+        Command::Shl => shift.map(|shift| Value::Int(lhs << shift)),
+        Command::Shr => shift.map(|shift| Value::Int(lhs >> shift)),
+        Command::Eq => Some(Value::Bool(lhs == rhs)),
+        Command::Lt => Some(Value::Bool(lhs < rhs)),
+        Command::Gt => Some(Value::Bool(lhs > rhs)),
+        Command::Le => Some(Value::Bool(lhs <= rhs)),
+        Command::Ge => Some(Value::Bool(lhs >= rhs)),
+        _ => None,
+    }
+}
+
+// Writes commands back out as source, one per line, with the bodies of
+// blocks indented
+#[allow(dead_code)]
+fn disassemble(commands: &[Command]) -> String {
+    let mut output = String::new();
+    disassemble_block(&mut output, commands, 0);
+    output
+}
+
+fn disassemble_block(output: &mut String, commands: &[Command], indent: usize) {
+    let line = |output: &mut String, text: &str| {
+        output.push_str(&"    ".repeat(indent));
+        output.push_str(text);
+        output.push('\n');
+    };
+
+    for command in commands {
+        line(output, &command.to_string());
+        match command {
+            Command::If(then, otherwise) => {
+                disassemble_block(output, then, indent + 1);
+                if !otherwise.is_empty() {
+                    line(output, "else");
+                    disassemble_block(output, otherwise, indent + 1);
+                }
+                line(output, "end");
+            }
+            Command::While(body) | Command::Repeat(_, body) => {
+                disassemble_block(output, body, indent + 1);
+                line(output, "end");
+            }
+            Command::Def(_, body) => {
+                disassemble_block(output, body, indent + 1);
+                line(output, "end");
+            }
+            _ => {}
+        }
+    }
+}
+
+// Programs can be compiled to bytes once and then loaded without parsing
+// again. Each command is a tag byte followed by its parameters. Lengths
+// and counts are little-endian u32s, except for the counts commands take
+// as parameters, which are u64s.
+#[allow(dead_code)]
+fn serialize_commands(commands: &[Command]) -> Vec<u8> {
+    let mut out = vec![];
+    write_commands(&mut out, commands);
+    out
+}
+
+#[allow(dead_code)]
+fn deserialize_commands(bytes: &[u8]) -> Result<Vec<Command>, EngineError> {
+    let mut reader = BytecodeReader {
+        bytes,
+        pos: 0,
+        depth: 0,
+    };
+    let commands = reader.commands()?;
+    if reader.pos != bytes.len() {
+        return Err(EngineError::BadBytecode);
+    }
+    Ok(commands)
+}
+
+fn write_commands(out: &mut Vec<u8>, commands: &[Command]) {
+    out.extend_from_slice(&(commands.len() as u32).to_le_bytes());
+    for command in commands {
+        write_command(out, command);
+    }
+}
+
+fn write_command(out: &mut Vec<u8>, command: &Command) {
+    match command {
+        Command::SetVar(name, value) => {
+            out.push(0);
+            write_string(out, name);
+            write_value(out, value);
+        }
+        Command::GetVar(name) => {
+            out.push(1);
+            write_string(out, name);
+        }
+        Command::PushVar(name) => {
+            out.push(2);
+            write_string(out, name);
+        }
+        Command::Push(value) => {
+            out.push(3);
+            write_value(out, value);
+        }
+        Command::Pop => out.push(4),
+        Command::Add => out.push(5),
+        Command::Sub => out.push(6),
+        Command::Mul => out.push(7),
+        Command::Div => out.push(8),
+        Command::Mod => out.push(9),
+        Command::Neg => out.push(10),
+        Command::Eq => out.push(11),
+        Command::Lt => out.push(12),
+        Command::Gt => out.push(13),
+        Command::Le => out.push(14),
+        Command::Ge => out.push(15),
+        Command::And => out.push(16),
+        Command::Or => out.push(17),
+        Command::Not => out.push(18),
+        Command::Dup => out.push(19),
+        Command::Swap => out.push(20),
+        Command::Over => out.push(21),
+        Command::Rot => out.push(22),
+        Command::Drop => out.push(23),
+        Command::Clear => out.push(24),
+        Command::Depth => out.push(25),
+        Command::Print => out.push(26),
+        Command::DelVar(name) => {
+            out.push(27);
+            write_string(out, name);
+        }
+        Command::MakeList(count) => {
+            out.push(28);
+            out.extend_from_slice(&(*count as u64).to_le_bytes());
+        }
+        Command::Index => out.push(29),
+        Command::Len => out.push(30),
+        Command::Append => out.push(31),
+        Command::Upper => out.push(32),
+        Command::Lower => out.push(33),
+        Command::Substr => out.push(34),
+        Command::Split => out.push(35),
+        Command::Peek => out.push(36),
+        Command::TypeOf => out.push(37),
+        Command::ToInt => out.push(38),
+        Command::ToStr => out.push(39),
+        Command::Label(name) => {
+            out.push(40);
+            write_string(out, name);
+        }
+        Command::Jmp(name) => {
+            out.push(41);
+            write_string(out, name);
+        }
+        Command::JmpIf(name) => {
+            out.push(42);
+            write_string(out, name);
+        }
+        Command::If(then, otherwise) => {
+            out.push(43);
+            write_commands(out, then);
+            write_commands(out, otherwise);
+        }
+        Command::While(body) => {
+            out.push(44);
+            write_commands(out, body);
+        }
+        Command::Def(name, body) => {
+            out.push(45);
+            write_string(out, name);
+            write_commands(out, body);
+        }
+        Command::Call(name) => {
+            out.push(46);
+            write_string(out, name);
+        }
+        Command::Pick(depth) => {
+            out.push(47);
+            out.extend_from_slice(&(*depth as u64).to_le_bytes());
+        }
+        Command::BitAnd => out.push(48),
+        Command::BitOr => out.push(49),
+        Command::BitXor => out.push(50),
+        Command::Shl => out.push(51),
+        Command::Shr => out.push(52),
+        Command::BitNot => out.push(53),
+        Command::Sum => out.push(54),
+        #[cfg(feature = "serde")]
+        Command::Dump(path) => {
+            out.push(55);
+            write_string(out, path);
+        }
+        #[cfg(feature = "serde")]
+        Command::Load(path) => {
+            out.push(56);
+            write_string(out, path);
+        }
+        Command::Sort => out.push(57),
+        Command::Reverse => out.push(58),
+        Command::Range => out.push(59),
+        Command::Contains => out.push(60),
+        Command::Scope => out.push(61),
+        Command::EndScope => out.push(62),
+        Command::SetConst(name, value) => {
+            out.push(63);
+            write_string(out, name);
+            write_value(out, value);
+        }
+        Command::ListVars => out.push(64),
+        Command::HasVar(name) => {
+            out.push(65);
+            write_string(out, name);
+        }
+        Command::SwapVar(a, b) => {
+            out.push(66);
+            write_string(out, a);
+            write_string(out, b);
+        }
+        Command::Inc(name) => {
+            out.push(67);
+            write_string(out, name);
+        }
+        Command::Dec(name) => {
+            out.push(68);
+            write_string(out, name);
+        }
+        Command::Min => out.push(69),
+        Command::Max => out.push(70),
+        Command::Pow => out.push(71),
+        Command::Abs => out.push(72),
+        Command::Trim => out.push(73),
+        Command::Replace => out.push(74),
+        Command::Join => out.push(75),
+        Command::Nip => out.push(76),
+        Command::Tuck => out.push(77),
+        Command::TwoDup => out.push(78),
+        Command::Caesar(shift) => {
+            out.push(79);
+            out.extend_from_slice(&shift.to_le_bytes());
+        }
+        Command::Format(count) => {
+            out.push(80);
+            out.extend_from_slice(&(*count as u64).to_le_bytes());
+        }
+        Command::Assert => out.push(81),
+        Command::AssertEq => out.push(82),
+        Command::Concat => out.push(83),
+        Command::Repeat(count, body) => {
+            out.push(84);
+            out.extend_from_slice(&(*count as u64).to_le_bytes());
+            write_commands(out, body);
+        }
+        Command::Count => out.push(85),
+        Command::Slice => out.push(86),
+    }
+}
+
+fn write_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Nothing => out.push(0),
+        Value::Int(i) => {
+            out.push(1);
+            out.extend_from_slice(&i.to_le_bytes());
+        }
+        Value::Float(x) => {
+            out.push(2);
+            out.extend_from_slice(&x.to_bits().to_le_bytes());
+        }
+        Value::Bool(b) => out.push(if *b { 4 } else { 3 }),
+        Value::String(s) => {
+            out.push(5);
+            write_string(out, s);
+        }
+        Value::List(items) => {
+            out.push(6);
+            out.extend_from_slice(&(items.len() as u32).to_le_bytes());
+            for item in items.iter() {
+                write_value(out, item);
+            }
+        }
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+// How deeply blocks may nest in bytecode. Reading recurses once per level,
+// so this keeps bad input from overflowing the stack.
+const MAX_BYTECODE_DEPTH: usize = 256;
+
+struct BytecodeReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    // How many blocks deep the reader currently is
+    depth: usize,
+}
+
+impl<'a> BytecodeReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], EngineError> {
+        match self.bytes.get(self.pos..self.pos.saturating_add(len)) {
+            Some(bytes) => {
+                self.pos += len;
+                Ok(bytes)
+            }
+            None => Err(EngineError::BadBytecode),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, EngineError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, EngineError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, EngineError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn count(&mut self) -> Result<usize, EngineError> {
+        usize::try_from(self.u64()?).map_err(|_| EngineError::BadBytecode)
+    }
+
+    fn string(&mut self) -> Result<String, EngineError> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| EngineError::BadBytecode)
+    }
+
+    fn value(&mut self) -> Result<Value, EngineError> {
+        let value = match self.byte()? {
+            0 => Value::Nothing,
+            1 => Value::Int(self.u64()? as i64),
+            2 => Value::Float(f64::from_bits(self.u64()?)),
+            3 => Value::Bool(false),
+            4 => Value::Bool(true),
+            5 => Value::String(self.string()?.into()),
+            6 => {
+                // Not preallocated, so a bad count can't ask for a huge buffer
+                let len = self.u32()?;
+                let mut items = vec![];
+                for _ in 0..len {
+                    items.push(self.value()?);
+                }
+                Value::List(Rc::new(items))
+            }
+            _ => return Err(EngineError::BadBytecode),
+        };
+        Ok(value)
+    }
+
+    fn commands(&mut self) -> Result<Vec<Command>, EngineError> {
+        if self.depth >= MAX_BYTECODE_DEPTH {
+            return Err(EngineError::BadBytecode);
+        }
+        self.depth += 1;
+
+        let len = self.u32()?;
+        let mut commands = vec![];
+        for _ in 0..len {
+            commands.push(self.command()?);
+        }
+
+        self.depth -= 1;
+        Ok(commands)
+    }
+
+    fn command(&mut self) -> Result<Command, EngineError> {
+        let command = match self.byte()? {
+            0 => {
+                let name = self.string()?;
+                let value = self.value()?;
+                Command::SetVar(name, value)
+            }
+            1 => Command::GetVar(self.string()?),
+            2 => Command::PushVar(self.string()?),
+            3 => Command::Push(self.value()?),
+            4 => Command::Pop,
+            5 => Command::Add,
+            6 => Command::Sub,
+            7 => Command::Mul,
+            8 => Command::Div,
+            9 => Command::Mod,
+            10 => Command::Neg,
+            11 => Command::Eq,
+            12 => Command::Lt,
+            13 => Command::Gt,
+            14 => Command::Le,
+            15 => Command::Ge,
+            16 => Command::And,
+            17 => Command::Or,
+            18 => Command::Not,
+            19 => Command::Dup,
+            20 => Command::Swap,
+            21 => Command::Over,
+            22 => Command::Rot,
+            23 => Command::Drop,
+            24 => Command::Clear,
+            25 => Command::Depth,
+            26 => Command::Print,
+            27 => Command::DelVar(self.string()?),
+            28 => Command::MakeList(self.count()?),
+            29 => Command::Index,
+            30 => Command::Len,
+            31 => Command::Append,
+            32 => Command::Upper,
+            33 => Command::Lower,
+            34 => Command::Substr,
+            35 => Command::Split,
+            36 => Command::Peek,
+            37 => Command::TypeOf,
+            38 => Command::ToInt,
+            39 => Command::ToStr,
+            40 => Command::Label(self.string()?),
+            41 => Command::Jmp(self.string()?),
+            42 => Command::JmpIf(self.string()?),
+            43 => {
+                let then = self.commands()?;
+                let otherwise = self.commands()?;
+                Command::If(then, otherwise)
+            }
+            44 => Command::While(self.commands()?),
+            45 => {
+                let name = self.string()?;
+                let body = Rc::new(self.commands()?);
+                Command::Def(name, body)
+            }
+            46 => Command::Call(self.string()?),
+            47 => Command::Pick(self.count()?),
+            48 => Command::BitAnd,
+            49 => Command::BitOr,
+            50 => Command::BitXor,
+            51 => Command::Shl,
+            52 => Command::Shr,
+            53 => Command::BitNot,
+            54 => Command::Sum,
+            #[cfg(feature = "serde")]
+            55 => Command::Dump(self.string()?),
+            #[cfg(feature = "serde")]
+            56 => Command::Load(self.string()?),
+            57 => Command::Sort,
+            58 => Command::Reverse,
+            59 => Command::Range,
+            60 => Command::Contains,
+            61 => Command::Scope,
+            62 => Command::EndScope,
+            63 => {
+                let name = self.string()?;
+                let value = self.value()?;
+                Command::SetConst(name, value)
+            }
+            64 => Command::ListVars,
+            65 => Command::HasVar(self.string()?),
+            66 => {
+                let a = self.string()?;
+                let b = self.string()?;
+                Command::SwapVar(a, b)
+            }
+            67 => Command::Inc(self.string()?),
+            68 => Command::Dec(self.string()?),
+            69 => Command::Min,
+            70 => Command::Max,
+            71 => Command::Pow,
+            72 => Command::Abs,
+            73 => Command::Trim,
+            74 => Command::Replace,
+            75 => Command::Join,
+            76 => Command::Nip,
+            77 => Command::Tuck,
+            78 => Command::TwoDup,
+            79 => Command::Caesar(self.u64()? as i64),
+            80 => Command::Format(self.count()?),
+            81 => Command::Assert,
+            82 => Command::AssertEq,
+            83 => Command::Concat,
+            84 => Command::Repeat(self.count()?, self.commands()?),
+            85 => Command::Count,
+            86 => Command::Slice,
+            _ => return Err(EngineError::BadBytecode),
+        };
+        Ok(command)
+    }
+}
+
+fn parse_var_name(var_name: &str) -> Result<String, EngineError> {
+    Ok(var_name.into())
+}
+
+fn unescape(val: &str) -> Result<String, EngineError> {
+    let mut output = String::new();
+    let mut chars = val.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            output.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => output.push('\n'),
+            Some('t') => output.push('\t'),
+            Some('\\') => output.push('\\'),
+            Some('"') => output.push('"'),
+            Some(other) => return Err(EngineError::BadEscape(other)),
+            None => return Err(EngineError::BadEscape('\\')),
+        }
+    }
+
+    Ok(output)
+}
+
+fn parse_string(val: &str) -> Result<Value, EngineError> {
+    if val.starts_with('\"') && val.ends_with('\"') && val.len() > 1 {
+        let inner = unescape(&val[1..(val.len() - 1)])?;
+
+        Ok(Value::String(inner.into()))
+    } else {
+        // Anything unquoted isn't a string literal
+        Err(EngineError::MismatchType)
+    }
+}
+
+fn parse_int(val: &str) -> Result<Value, EngineError> {
+    match parse_int_literal(val) {
+        Some(x) => Ok(Value::Int(x)),
+        // A token that isn't a number is rejected as text
+        None => Err(EngineError::TypeMismatch {
+            expected: Type::Int,
+            found: Type::String,
+        }),
+    }
+}
+
+fn parse_int_literal(val: &str) -> Option<i64> {
+    let (sign, digits) = match val.strip_prefix('-') {
+        Some(rest) => ("-", rest),
+        None => ("", val),
+    };
+
+    let (radix, digits) = if let Some(rest) = digits.strip_prefix("0x") {
+        (16, rest)
+    } else if let Some(rest) = digits.strip_prefix("0o") {
+        (8, rest)
+    } else if let Some(rest) = digits.strip_prefix("0b") {
+        (2, rest)
+    } else {
+        (10, digits)
+    };
+
+    // from_str_radix would otherwise accept a second sign after the prefix
+    if digits.starts_with('+') || digits.starts_with('-') {
+        return None;
+    }
+
+    // Underscores may only separate digits
+    if digits.starts_with('_') || digits.ends_with('_') || digits.contains("__") {
+        return None;
+    }
+    let digits = digits.replace('_', "");
+
+    i64::from_str_radix(&format!("{}{}", sign, digits), radix).ok()
+}
+
+fn parse_float(val: &str) -> Result<Value, EngineError> {
+    let result = val.parse::<f64>();
+
+    match result {
+        Ok(x) => Ok(Value::Float(x)),
+        _ => Err(EngineError::TypeMismatch {
+            expected: Type::Float,
+            found: Type::String,
+        }),
+    }
+}
+
+fn parse_value(val: &str) -> Result<Value, EngineError> {
+    if val.starts_with("\"") && val.ends_with("\"") && val.len() > 1 {
+        // Parse the string
+        parse_string(val)
+    } else if val == "none" {
+        Ok(Value::Nothing)
+    } else if val == "true" {
+        Ok(Value::Bool(true))
+    } else if val == "false" {
+        Ok(Value::Bool(false))
+    } else if val.contains('.') || matches!(val, "inf" | "-inf" | "nan") {
+        // Parse the float
+        parse_float(val)
+    } else {
+        // Parse the number
+        parse_int(val)
+    }
+}
+
+fn parse_set(input: &[&str]) -> Result<Command, EngineError> {
+    if input.len() != 3 {
+        return Err(EngineError::MismatchNumParams);
+    }
+
+    let var_name = parse_var_name(input[1])?;
+    let value = parse_value(input[2])?;
+
+    Ok(Command::SetVar(var_name, value))
+}
+
+fn parse_const(input: &[&str]) -> Result<Command, EngineError> {
+    if input.len() != 3 {
+        return Err(EngineError::MismatchNumParams);
+    }
+
+    let var_name = parse_var_name(input[1])?;
+    let value = parse_value(input[2])?;
+
+    Ok(Command::SetConst(var_name, value))
+}
+
+fn parse_get(input: &[&str]) -> Result<Command, EngineError> {
+    if input.len() != 2 {
+        return Err(EngineError::MismatchNumParams);
+    }
+
+    let var_name = parse_var_name(input[1])?;
+
+    Ok(Command::GetVar(var_name))
+}
+
+fn parse_pushvar(input: &[&str]) -> Result<Command, EngineError> {
+    if input.len() != 2 {
+        return Err(EngineError::MismatchNumParams);
+    }
+
+    let var_name = parse_var_name(input[1])?;
+
+    Ok(Command::PushVar(var_name))
+}
+
+fn parse_del(input: &[&str]) -> Result<Command, EngineError> {
+    if input.len() != 2 {
+        return Err(EngineError::MismatchNumParams);
+    }
+
+    let var_name = parse_var_name(input[1])?;
+
+    Ok(Command::DelVar(var_name))
+}
+
+fn parse_count(val: &str) -> Result<usize, EngineError> {
+    match val.parse::<usize>() {
+        Ok(x) => Ok(x),
+        _ => Err(EngineError::TypeMismatch {
+            expected: Type::Int,
+            found: Type::String,
+        }),
+    }
+}
+
+fn parse_makelist(input: &[&str]) -> Result<Command, EngineError> {
+    if input.len() != 2 {
+        return Err(EngineError::MismatchNumParams);
+    }
+
+    let count = parse_count(input[1])?;
+
+    Ok(Command::MakeList(count))
+}
+
+fn parse_label(input: &[&str]) -> Result<Command, EngineError> {
+    if input.len() != 2 {
+        return Err(EngineError::MismatchNumParams);
+    }
+
+    // The trailing colon in `label name:` is optional
+    let name = input[1].strip_suffix(':').unwrap_or(input[1]);
+    let name = parse_var_name(name)?;
+
+    Ok(Command::Label(name))
+}
+
+fn parse_jmp(input: &[&str]) -> Result<Command, EngineError> {
+    if input.len() != 2 {
+        return Err(EngineError::MismatchNumParams);
+    }
+
+    let name = parse_var_name(input[1])?;
+
+    Ok(Command::Jmp(name))
+}
+
+fn parse_jmpif(input: &[&str]) -> Result<Command, EngineError> {
+    if input.len() != 2 {
+        return Err(EngineError::MismatchNumParams);
+    }
+
+    let name = parse_var_name(input[1])?;
+
+    Ok(Command::JmpIf(name))
+}
+
+fn parse_call(input: &[&str]) -> Result<Command, EngineError> {
+    if input.len() != 2 {
+        return Err(EngineError::MismatchNumParams);
+    }
+
+    let name = parse_var_name(input[1])?;
+
+    Ok(Command::Call(name))
+}
+
+fn parse_pick(input: &[&str]) -> Result<Command, EngineError> {
+    if input.len() != 2 {
+        return Err(EngineError::MismatchNumParams);
+    }
+
+    let depth = parse_count(input[1])?;
+
+    Ok(Command::Pick(depth))
+}
+
+#[cfg(feature = "serde")]
+fn parse_path(input: &[&str]) -> Result<String, EngineError> {
+    if input.len() != 2 {
+        return Err(EngineError::MismatchNumParams);
+    }
+
+    let path = parse_string(input[1])?;
+
+    Ok(path.to_string())
+}
+
+fn parse_has(input: &[&str]) -> Result<Command, EngineError> {
+    if input.len() != 2 {
+        return Err(EngineError::MismatchNumParams);
+    }
+
+    let var_name = parse_var_name(input[1])?;
+
+    Ok(Command::HasVar(var_name))
+}
+
+fn parse_swapvar(input: &[&str]) -> Result<Command, EngineError> {
+    if input.len() != 3 {
+        return Err(EngineError::MismatchNumParams);
+    }
+
+    let a = parse_var_name(input[1])?;
+    let b = parse_var_name(input[2])?;
+
+    Ok(Command::SwapVar(a, b))
+}
+
+// For commands that take just a variable name
+fn parse_var_param(input: &[&str]) -> Result<String, EngineError> {
+    if input.len() != 2 {
+        return Err(EngineError::MismatchNumParams);
+    }
+
+    parse_var_name(input[1])
+}
+
+fn parse_caesar(input: &[&str]) -> Result<Command, EngineError> {
+    if input.len() != 2 {
+        return Err(EngineError::MismatchNumParams);
+    }
+
+    match parse_int(input[1])? {
+        Value::Int(shift) => Ok(Command::Caesar(shift)),
+        _ => Err(EngineError::MismatchType),
+    }
+}
+
+fn parse_format(input: &[&str]) -> Result<Command, EngineError> {
+    if input.len() != 2 {
+        return Err(EngineError::MismatchNumParams);
+    }
+
+    let count = parse_count(input[1])?;
+
+    Ok(Command::Format(count))
+}
+
+fn parse_push(input: &[&str]) -> Result<Command, EngineError> {
+    if input.len() != 2 {
+        return Err(EngineError::MismatchNumParams);
+    }
+
+    let val = parse_value(input[1])?;
+
+    Ok(Command::Push(val))
+}
+
+// Cuts the line at the first `#` that isn't inside a string literal
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (idx, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..idx],
+            _ => {}
+        }
+    }
+
+    line
+}
+
+// Splits a line on whitespace, keeping a double-quoted string (quotes
+// included) together as one token even when it contains spaces. A `;`
+// outside of a string becomes its own token so commands can share a line.
+// Each token comes with the column (in characters, from 1) where it begins.
+fn tokenize(line: &str) -> Vec<(usize, String)> {
+    let mut tokens = vec![];
+    let mut current = String::new();
+    let mut start = 1;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (idx, c) in line.chars().enumerate() {
+        if current.is_empty() {
+            start = idx + 1;
+        }
+
+        match c {
+            _ if escaped => {
+                escaped = false;
+                current.push(c);
+            }
+            '\\' if in_string => {
+                escaped = true;
+                current.push(c);
+            }
+            '"' => {
+                in_string = !in_string;
+                current.push(c);
+            }
+            c if c.is_ascii_whitespace() && !in_string => {
+                if !current.is_empty() {
+                    tokens.push((start, std::mem::take(&mut current)));
+                }
+            }
+            ';' if !in_string => {
+                if !current.is_empty() {
+                    tokens.push((start, std::mem::take(&mut current)));
+                }
+                tokens.push((idx + 1, ";".into()));
+            }
+            _ => current.push(c),
+        }
+    }
+
+    if !current.is_empty() {
+        tokens.push((start, current));
+    }
+
+    tokens
+}
+
+fn parse(input: &str) -> Result<Vec<Command>, EngineError> {
+    // set a 100
+    // get a
+
+    let mut blocks = vec![Block {
+        kind: BlockKind::Top,
+        commands: vec![],
+    }];
+
+    for (idx, line) in input.lines().enumerate() {
+        parse_line(line, idx + 1, &mut blocks)?;
+    }
+
+    // Anything still open at the end of the input is missing its `end`
+    match blocks.pop() {
+        Some(Block {
+            kind: BlockKind::Top,
+            commands,
+        }) if blocks.is_empty() => Ok(commands),
+        _ => Err(EngineError::UnbalancedBlock),
+    }
+}
+
+// A block of commands that's still being parsed
+struct Block {
+    kind: BlockKind,
+    commands: Vec<Command>,
+}
+
+enum BlockKind {
+    Top,
+    If,
+    While,
+    Repeat(usize),
+    Def(String),
+    // Holds the commands of the `if` branch while the `else` branch is parsed
+    Else(Vec<Command>),
+}
+
+fn parse_line(line: &str, line_number: usize, blocks: &mut Vec<Block>) -> Result<(), EngineError> {
+    let tokens = tokenize(strip_comment(line));
+
+    for segment in tokens.split(|(_, x)| x == ";") {
+        let command: Vec<_> = segment.iter().map(|(_, x)| x.as_str()).collect();
+
+        parse_command(&command, blocks).map_err(|err| {
+            // Problems with the command as a whole point at its name, and
+            // problems with a value point at the value, which comes last
+            let token = match err {
+                EngineError::UnknownCommand(_)
+                | EngineError::MismatchNumParams
+                | EngineError::UnbalancedBlock => segment.first(),
+                _ => segment.last(),
+            };
+            EngineError::AtLine {
+                span: Span {
+                    line: line_number,
+                    col: token.map_or(1, |(col, _)| *col),
+                },
+                source: Box::new(err),
+            }
+        })?;
+    }
+
+    Ok(())
+}
+
+fn parse_command(command: &[&str], blocks: &mut Vec<Block>) -> Result<(), EngineError> {
+    if parse_block(command, blocks)? {
+        return Ok(());
+    }
+
+    let output = match blocks.last_mut() {
+        Some(block) => &mut block.commands,
+        None => return Err(EngineError::UnbalancedBlock),
+    };
+
+    match command.first() {
+        Some(x) if *x == "set" => {
+            output.push(parse_set(command)?);
+        }
+        Some(x) if *x == "get" => {
+            output.push(parse_get(command)?);
+        }
+        Some(x) if *x == "push" => {
+            output.push(parse_push(command)?);
+        }
+        Some(x) if *x == "pushvar" => {
+            output.push(parse_pushvar(command)?);
+        }
+        Some(x) if *x == "pop" => {
+            output.push(Command::Pop);
+        }
+        Some(x) if *x == "add" => {
+            output.push(Command::Add);
+        }
+        Some(x) if *x == "sub" => {
+            output.push(Command::Sub);
+        }
+        Some(x) if *x == "mul" => {
+            output.push(Command::Mul);
+        }
+        Some(x) if *x == "div" => {
+            output.push(Command::Div);
+        }
+        Some(x) if *x == "mod" => {
+            output.push(Command::Mod);
+        }
+        Some(x) if *x == "neg" => {
+            if command.len() != 1 {
+                return Err(EngineError::MismatchNumParams);
+            }
+            output.push(Command::Neg);
+        }
+        Some(x) if *x == "eq" => {
+            output.push(Command::Eq);
+        }
+        Some(x) if *x == "lt" => {
+            output.push(Command::Lt);
+        }
+        Some(x) if *x == "gt" => {
+            output.push(Command::Gt);
+        }
+        Some(x) if *x == "le" => {
+            output.push(Command::Le);
+        }
+        Some(x) if *x == "ge" => {
+            output.push(Command::Ge);
+        }
+        Some(x) if *x == "and" => {
+            output.push(Command::And);
+        }
+        Some(x) if *x == "or" => {
+            output.push(Command::Or);
+        }
+        Some(x) if *x == "not" => {
+            output.push(Command::Not);
+        }
+        Some(x) if *x == "dup" => {
+            output.push(Command::Dup);
+        }
+        Some(x) if *x == "swap" => {
+            output.push(Command::Swap);
+        }
+        Some(x) if *x == "over" => {
+            output.push(Command::Over);
+        }
+        Some(x) if *x == "rot" => {
+            output.push(Command::Rot);
+        }
+        Some(x) if *x == "drop" => {
+            output.push(Command::Drop);
+        }
+        Some(x) if *x == "clear" => {
+            output.push(Command::Clear);
+        }
+        Some(x) if *x == "depth" => {
+            output.push(Command::Depth);
+        }
+        Some(x) if *x == "print" => {
+            output.push(Command::Print);
+        }
+        Some(x) if *x == "del" => {
+            output.push(parse_del(command)?);
+        }
+        Some(x) if *x == "makelist" => {
+            output.push(parse_makelist(command)?);
+        }
+        Some(x) if *x == "index" => {
+            output.push(Command::Index);
+        }
+        Some(x) if *x == "len" => {
+            output.push(Command::Len);
+        }
+        Some(x) if *x == "append" => {
+            output.push(Command::Append);
+        }
+        Some(x) if *x == "upper" => {
+            output.push(Command::Upper);
+        }
+        Some(x) if *x == "lower" => {
+            output.push(Command::Lower);
+        }
+        Some(x) if *x == "substr" => {
+            output.push(Command::Substr);
+        }
+        Some(x) if *x == "split" => {
+            output.push(Command::Split);
+        }
+        Some(x) if *x == "peek" => {
+            output.push(Command::Peek);
+        }
+        Some(x) if *x == "typeof" => {
+            output.push(Command::TypeOf);
+        }
+        Some(x) if *x == "toint" => {
+            output.push(Command::ToInt);
+        }
+        Some(x) if *x == "tostr" => {
+            output.push(Command::ToStr);
+        }
+        Some(x) if *x == "label" => {
+            output.push(parse_label(command)?);
+        }
+        Some(x) if *x == "jmp" => {
+            output.push(parse_jmp(command)?);
+        }
+        Some(x) if *x == "jmpif" => {
+            output.push(parse_jmpif(command)?);
+        }
+        Some(x) if *x == "call" => {
+            output.push(parse_call(command)?);
+        }
+        Some(x) if *x == "pick" => {
+            output.push(parse_pick(command)?);
+        }
+        Some(x) if *x == "band" => {
+            output.push(Command::BitAnd);
+        }
+        Some(x) if *x == "bor" => {
+            output.push(Command::BitOr);
+        }
+        Some(x) if *x == "bxor" => {
+            output.push(Command::BitXor);
+        }
+        Some(x) if *x == "shl" => {
+            output.push(Command::Shl);
+        }
+        Some(x) if *x == "shr" => {
+            output.push(Command::Shr);
+        }
+        Some(x) if *x == "bnot" => {
+            output.push(Command::BitNot);
+        }
+        Some(x) if *x == "sum" => {
+            output.push(Command::Sum);
+        }
+        #[cfg(feature = "serde")]
+        Some(x) if *x == "dump" => {
+            output.push(Command::Dump(parse_path(command)?));
+        }
+        #[cfg(feature = "serde")]
+        Some(x) if *x == "load" => {
+            output.push(Command::Load(parse_path(command)?));
+        }
+        Some(x) if *x == "sort" => {
+            output.push(Command::Sort);
+        }
+        Some(x) if *x == "reverse" => {
+            output.push(Command::Reverse);
+        }
+        Some(x) if *x == "range" => {
+            output.push(Command::Range);
+        }
+        Some(x) if *x == "contains" => {
+            output.push(Command::Contains);
+        }
+        Some(x) if *x == "scope" => {
+            output.push(Command::Scope);
+        }
+        Some(x) if *x == "endscope" => {
+            output.push(Command::EndScope);
+        }
+        Some(x) if *x == "const" => {
+            output.push(parse_const(command)?);
+        }
+        Some(x) if *x == "vars" => {
+            output.push(Command::ListVars);
+        }
+        Some(x) if *x == "has" => {
+            output.push(parse_has(command)?);
+        }
+        Some(x) if *x == "swapvar" => {
+            output.push(parse_swapvar(command)?);
+        }
+        Some(x) if *x == "inc" => {
+            output.push(Command::Inc(parse_var_param(command)?));
+        }
+        Some(x) if *x == "dec" => {
+            output.push(Command::Dec(parse_var_param(command)?));
+        }
+        Some(x) if *x == "min" => {
+            output.push(Command::Min);
+        }
+        Some(x) if *x == "max" => {
+            output.push(Command::Max);
+        }
+        Some(x) if *x == "pow" => {
+            output.push(Command::Pow);
+        }
+        Some(x) if *x == "abs" => {
+            output.push(Command::Abs);
+        }
+        Some(x) if *x == "trim" => {
+            output.push(Command::Trim);
+        }
+        Some(x) if *x == "replace" => {
+            output.push(Command::Replace);
+        }
+        Some(x) if *x == "join" => {
+            output.push(Command::Join);
+        }
+        Some(x) if *x == "nip" => {
+            output.push(Command::Nip);
+        }
+        Some(x) if *x == "tuck" => {
+            output.push(Command::Tuck);
+        }
+        Some(x) if *x == "2dup" => {
+            output.push(Command::TwoDup);
+        }
+        Some(x) if *x == "caesar" => {
+            output.push(parse_caesar(command)?);
+        }
+        Some(x) if *x == "format" => {
+            output.push(parse_format(command)?);
+        }
+        Some(x) if *x == "assert" => {
+            output.push(Command::Assert);
+        }
+        Some(x) if *x == "assert_eq" => {
+            output.push(Command::AssertEq);
+        }
+        Some(x) if *x == "concat" => {
+            output.push(Command::Concat);
+        }
+        Some(x) if *x == "count" => {
+            output.push(Command::Count);
+        }
+        Some(x) if *x == "slice" => {
+            output.push(Command::Slice);
+        }
+        Some(name) => return Err(EngineError::UnknownCommand(name.to_string())),
+        None => {}
+    }
+
+    Ok(())
+}
+
+// Handles the keywords that open and close blocks, returning true if the
+// command was one of them
+fn parse_block(command: &[&str], blocks: &mut Vec<Block>) -> Result<bool, EngineError> {
+    match command.first() {
+        Some(x) if *x == "if" => {
+            if command.len() != 1 {
+                return Err(EngineError::MismatchNumParams);
+            }
+            blocks.push(Block {
+                kind: BlockKind::If,
+                commands: vec![],
+            });
+        }
+        Some(x) if *x == "while" => {
+            if command.len() != 1 {
+                return Err(EngineError::MismatchNumParams);
+            }
+            blocks.push(Block {
+                kind: BlockKind::While,
+                commands: vec![],
+            });
+        }
+        Some(x) if *x == "repeat" => {
+            if command.len() != 2 {
+                return Err(EngineError::MismatchNumParams);
+            }
+            blocks.push(Block {
+                kind: BlockKind::Repeat(parse_count(command[1])?),
+                commands: vec![],
+            });
+        }
+        Some(x) if *x == "def" => {
+            if command.len() != 2 {
+                return Err(EngineError::MismatchNumParams);
+            }
+            blocks.push(Block {
+                kind: BlockKind::Def(parse_var_name(command[1])?),
+                commands: vec![],
+            });
+        }
+        Some(x) if *x == "else" => {
+            if command.len() != 1 {
+                return Err(EngineError::MismatchNumParams);
+            }
+            match blocks.last_mut() {
+                Some(block) if matches!(block.kind, BlockKind::If) => {
+                    block.kind = BlockKind::Else(std::mem::take(&mut block.commands));
+                }
+                _ => return Err(EngineError::UnbalancedBlock),
+            }
+        }
+        Some(x) if *x == "end" => {
+            if command.len() != 1 {
+                return Err(EngineError::MismatchNumParams);
+            }
+            let finished = match blocks.pop() {
+                Some(Block {
+                    kind: BlockKind::If,
+                    commands,
+                }) => Command::If(commands, vec![]),
+                Some(Block {
+                    kind: BlockKind::Else(then),
+                    commands,
+                }) => Command::If(then, commands),
+                Some(Block {
+                    kind: BlockKind::While,
+                    commands,
+                }) => Command::While(commands),
+                Some(Block {
+                    kind: BlockKind::Repeat(count),
+                    commands,
+                }) => Command::Repeat(count, commands),
+                Some(Block {
+                    kind: BlockKind::Def(name),
+                    commands,
+                }) => Command::Def(name, Rc::new(commands)),
+                _ => return Err(EngineError::UnbalancedBlock),
+            };
+            match blocks.last_mut() {
+                Some(parent) => parent.commands.push(finished),
+                None => return Err(EngineError::UnbalancedBlock),
+            }
+        }
+        _ => return Ok(false),
+    }
+
+    Ok(true)
+}
+
+#[derive(Clone, PartialEq)]
+struct Typechecker {
+    // Scoped the same way as the evaluator's variables
+    vars: Vec<HashMap<String, Type>>,
+    stack: Vec<Type>,
+}
+
+impl Typechecker {
+    fn new() -> Typechecker {
+        Self {
+            vars: vec![HashMap::new()],
+            stack: vec![],
+        }
+    }
+
+    // Starts from the types of whatever the evaluator already holds, so that
+    // programs run against an existing evaluator can be checked
+    fn for_evaluator(evaluator: &Evaluator) -> Typechecker {
+        let mut typechecker = Self::new();
+
+        typechecker.vars = evaluator
+            .vars
+            .iter()
+            .map(|frame| {
+                frame
+                    .iter()
+                    .map(|(name, value)| (name.clone(), Type::of(value)))
+                    .collect()
+            })
+            .collect();
+        typechecker.stack = evaluator.stack.iter().map(Type::of).collect();
+
+        typechecker
+    }
+
+    fn get_var(&self, name: &str) -> Option<&Type> {
+        self.vars.iter().rev().find_map(|frame| frame.get(name))
+    }
+
+    fn set_var(&mut self, name: &str, ty: Type) {
+        let frame = self.vars.last_mut().expect("global frame is never removed");
+        frame.insert(name.into(), ty);
+    }
+
+    fn var_mut(&mut self, name: &str) -> Option<&mut Type> {
+        self.vars
+            .iter_mut()
+            .rev()
+            .find_map(|frame| frame.get_mut(name))
+    }
+
+    fn pop(&mut self) -> Result<Type, EngineError> {
+        match self.stack.pop() {
+            Some(ty) => Ok(ty),
+            None => Err(EngineError::EmptyStack),
+        }
+    }
+
+    fn peek(&self, depth: usize) -> Result<Type, EngineError> {
+        match self
+            .stack
+            .len()
+            .checked_sub(1)
+            .and_then(|top| top.checked_sub(depth))
+        {
+            Some(idx) => Ok(self.stack[idx].clone()),
+            None => Err(EngineError::EmptyStack),
+        }
+    }
+
+    // Updates `output` with the type of any result the command produces.
+    // Returns false if the checker can't follow the program past this command.
+    fn typecheck_command(
+        &mut self,
+        command: &Command,
+        output: &mut Type,
+    ) -> Result<bool, EngineError> {
+        match command {
+            Command::SetVar(name, value) | Command::SetConst(name, value) => {
+                self.set_var(name, Type::of(value))
+            }
+            Command::GetVar(name) => match self.get_var(name) {
+                Some(ty) => *output = ty.clone(),
+                None => return Err(EngineError::MissingVariable(name.into())),
+            },
+            Command::PushVar(name) => match self.get_var(name) {
+                Some(ty) => self.stack.push(ty.clone()),
+                None => return Err(EngineError::MissingVariable(name.into())),
+            },
+            Command::Push(value) => self.stack.push(Type::of(value)),
+            Command::Pop => *output = self.pop()?,
+            Command::Add => {
+                let lhs = self.pop()?;
+                let rhs = self.pop()?;
+
+                let result = match (lhs, rhs) {
+                    (Type::String, Type::String | Type::Any) | (Type::Any, Type::String) => {
+                        Type::String
+                    }
+                    (lhs, rhs) => match numeric_type(&lhs, &rhs) {
+                        Some(ty) => ty,
+                        None => return Err(add_mismatch(lhs, rhs)),
+                    },
+                };
+                self.stack.push(result);
+            }
+            Command::Sub | Command::Div => {
+                let rhs = self.pop()?;
+                let lhs = self.pop()?;
+
+                match numeric_type(&lhs, &rhs) {
+                    Some(ty) => self.stack.push(ty),
+                    None => return Err(EngineError::MismatchType),
+                }
+            }
+            Command::Mul => {
+                let rhs = self.pop()?;
+                let lhs = self.pop()?;
+
+                let result = match (lhs, rhs) {
+                    (Type::String, Type::Int | Type::Any)
+                    | (Type::Int | Type::Any, Type::String) => Type::String,
+                    (lhs, rhs) => match numeric_type(&lhs, &rhs) {
+                        Some(ty) => ty,
+                        None => return Err(EngineError::MismatchType),
+                    },
+                };
+                self.stack.push(result);
+            }
+            Command::Mod => match (self.pop()?, self.pop()?) {
+                (Type::Int | Type::Any, Type::Int | Type::Any) => self.stack.push(Type::Int),
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Neg => match self.pop()? {
+                ty @ (Type::Int | Type::Float | Type::Any) => self.stack.push(ty),
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::BitAnd | Command::BitOr | Command::BitXor | Command::Shl | Command::Shr => {
+                match (self.pop()?, self.pop()?) {
+                    (Type::Int | Type::Any, Type::Int | Type::Any) => self.stack.push(Type::Int),
+                    _ => return Err(EngineError::MismatchType),
+                }
+            }
+            Command::BitNot => match self.pop()? {
+                Type::Int | Type::Any => self.stack.push(Type::Int),
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Sum => {
+                for ty in std::mem::take(&mut self.stack) {
+                    if !matches!(ty, Type::Int | Type::Any) {
+                        return Err(EngineError::MismatchType);
+                    }
+                }
+                self.stack.push(Type::Int);
+            }
+            Command::Eq => {
+                self.pop()?;
+                self.pop()?;
+                self.stack.push(Type::Bool);
+            }
+            Command::Lt | Command::Gt | Command::Le | Command::Ge => {
+                let rhs = self.pop()?;
+                let lhs = self.pop()?;
+
+                match (&lhs, &rhs) {
+                    (Type::String | Type::Any, Type::String | Type::Any) => {}
+                    _ if numeric_type(&lhs, &rhs).is_some() => {}
+                    _ => return Err(EngineError::MismatchType),
+                }
+                self.stack.push(Type::Bool);
+            }
+            Command::And | Command::Or => match (self.pop()?, self.pop()?) {
+                (Type::Bool | Type::Any, Type::Bool | Type::Any) => self.stack.push(Type::Bool),
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Not => match self.pop()? {
+                Type::Bool | Type::Any => self.stack.push(Type::Bool),
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Dup => {
+                let ty = self.peek(0)?;
+                self.stack.push(ty);
+            }
+            Command::Swap => {
+                let top = self.pop()?;
+                let below = self.pop()?;
+                self.stack.push(top);
+                self.stack.push(below);
+            }
+            Command::Over => {
+                let ty = self.peek(1)?;
+                self.stack.push(ty);
+            }
+            Command::Pick(depth) => {
+                let ty = self.peek(*depth)?;
+                self.stack.push(ty);
+            }
+            Command::Rot => {
+                self.peek(2)?;
+                let len = self.stack.len();
+                self.stack[(len - 3)..].rotate_left(1);
+            }
+            Command::Drop => {
+                self.pop()?;
+            }
+            Command::Clear => self.stack.clear(),
+            Command::Depth => self.stack.push(Type::Int),
+            Command::Print => {
+                self.peek(0)?;
+            }
+            Command::DelVar(name) => {
+                let removed = self
+                    .vars
+                    .iter_mut()
+                    .rev()
+                    .find_map(|frame| frame.remove(name));
+                if removed.is_none() {
+                    return Err(EngineError::MissingVariable(name.into()));
+                }
+            }
+            Command::MakeList(count) => {
+                let len = self.stack.len();
+                if len < *count {
+                    return Err(EngineError::EmptyStack);
+                }
+                self.stack.truncate(len - count);
+                self.stack.push(Type::List);
+            }
+            Command::Index => match (self.pop()?, self.pop()?) {
+                (Type::Int | Type::Any, Type::List | Type::Any) => self.stack.push(Type::Any),
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Len => match self.pop()? {
+                Type::List | Type::String | Type::Any => self.stack.push(Type::Int),
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Append => {
+                self.pop()?;
+                match self.pop()? {
+                    Type::List | Type::Any => self.stack.push(Type::List),
+                    _ => return Err(EngineError::MismatchType),
+                }
+            }
+            Command::Upper | Command::Lower => match self.pop()? {
+                Type::String | Type::Any => self.stack.push(Type::String),
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Substr => match (self.pop()?, self.pop()?, self.pop()?) {
+                (Type::Int | Type::Any, Type::Int | Type::Any, Type::String | Type::Any) => {
+                    self.stack.push(Type::String)
+                }
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Split => match (self.pop()?, self.pop()?) {
+                (Type::String | Type::Any, Type::String | Type::Any) => self.stack.push(Type::List),
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Peek => *output = self.peek(0)?,
+            Command::TypeOf => {
+                self.pop()?;
+                self.stack.push(Type::String);
+            }
+            Command::ToInt => match self.pop()? {
+                Type::String | Type::Any => self.stack.push(Type::Int),
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::ToStr => {
+                self.pop()?;
+                self.stack.push(Type::String);
+            }
+            // What's on the stack at a label depends on how it was reached,
+            // so jumps end the static check and the rest is left to runtime
+            Command::Label(_) | Command::Jmp(_) | Command::JmpIf(_) => return Ok(false),
+            // Function bodies are checked when they run, since their effect
+            // depends on what's on the stack at the call
+            Command::Def(..) => {}
+            Command::Call(_) => return Ok(false),
+            #[cfg(feature = "serde")]
+            Command::Dump(_) => {}
+            // Whatever was saved isn't known until it's loaded
+            #[cfg(feature = "serde")]
+            Command::Load(_) => return Ok(false),
+            Command::If(then, otherwise) => {
+                match self.pop()? {
+                    Type::Bool | Type::Any => {}
+                    _ => return Err(EngineError::MismatchType),
+                }
+
+                let mut then_checker = self.clone();
+                let mut then_output = output.clone();
+                let mut else_checker = self.clone();
+                let mut else_output = output.clone();
+                if !then_checker.typecheck_block(then, &mut then_output)?
+                    || !else_checker.typecheck_block(otherwise, &mut else_output)?
+                {
+                    return Ok(false);
+                }
+
+                // Branches that leave different amounts on the stack, or in
+                // different scopes, can't be followed statically
+                if then_checker.stack.len() != else_checker.stack.len()
+                    || then_checker.vars.len() != else_checker.vars.len()
+                {
+                    return Ok(false);
+                }
+
+                then_checker.merge(else_checker);
+                *self = then_checker;
+                *output = join(then_output, else_output);
+            }
+            Command::While(body) => {
+                match self.pop()? {
+                    Type::Bool | Type::Any => {}
+                    _ => return Err(EngineError::MismatchType),
+                }
+
+                // Types can widen from one iteration to the next, so keep
+                // checking the body until they settle
+                loop {
+                    let mut body_checker = self.clone();
+                    let mut body_output = output.clone();
+                    if !body_checker.typecheck_block(body, &mut body_output)? {
+                        return Ok(false);
+                    }
+                    match body_checker.pop()? {
+                        Type::Bool | Type::Any => {}
+                        _ => return Err(EngineError::MismatchType),
+                    }
+                    if body_checker.stack.len() != self.stack.len()
+                        || body_checker.vars.len() != self.vars.len()
+                    {
+                        return Ok(false);
+                    }
+
+                    let before = self.clone();
+                    self.merge(body_checker);
+                    *output = join(output.clone(), body_output);
+                    if *self == before {
+                        break;
+                    }
+                }
+            }
+            Command::Repeat(count, body) => {
+                if *count == 0 {
+                    return Ok(true);
+                }
+
+                // Like while, except the body runs at least once
+                let mut first = true;
+                loop {
+                    let mut body_checker = self.clone();
+                    let mut body_output = output.clone();
+                    if !body_checker.typecheck_block(body, &mut body_output)? {
+                        return Ok(false);
+                    }
+                    if body_checker.stack.len() != self.stack.len()
+                        || body_checker.vars.len() != self.vars.len()
+                    {
+                        return Ok(false);
+                    }
+
+                    let before = self.clone();
+                    if first {
+                        *self = body_checker;
+                        *output = body_output;
+                        first = false;
+                    } else {
+                        self.merge(body_checker);
+                        *output = join(output.clone(), body_output);
+                    }
+                    if *self == before {
+                        break;
+                    }
+                }
+            }
+            Command::Sort => match self.pop()? {
+                Type::List | Type::Any => self.stack.push(Type::List),
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Reverse => match self.pop()? {
+                ty @ (Type::List | Type::String | Type::Any) => self.stack.push(ty),
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Range => match (self.pop()?, self.pop()?) {
+                (Type::Int | Type::Any, Type::Int | Type::Any) => self.stack.push(Type::List),
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Contains => match (self.pop()?, self.pop()?) {
+                (_, Type::List | Type::Any) | (Type::String | Type::Any, Type::String) => {
+                    self.stack.push(Type::Bool)
+                }
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Scope => self.vars.push(HashMap::new()),
+            Command::EndScope => {
+                if self.vars.len() == 1 {
+                    return Err(EngineError::UnbalancedBlock);
+                }
+                self.vars.pop();
+            }
+            Command::ListVars => self.stack.push(Type::List),
+            // Code after `has` usually only touches the variable if it exists,
+            // which can't be followed statically
+            Command::HasVar(_) => return Ok(false),
+            Command::SwapVar(a, b) => {
+                let ty_a = match self.get_var(a) {
+                    Some(ty) => ty.clone(),
+                    None => return Err(EngineError::MissingVariable(a.into())),
+                };
+                let ty_b = match self.var_mut(b) {
+                    Some(ty) => std::mem::replace(ty, ty_a),
+                    None => return Err(EngineError::MissingVariable(b.into())),
+                };
+                if let Some(ty) = self.var_mut(a) {
+                    *ty = ty_b;
+                }
+            }
+            Command::Inc(name) | Command::Dec(name) => match self.get_var(name) {
+                Some(Type::Int | Type::Any) => {}
+                Some(_) => return Err(EngineError::MismatchType),
+                None => return Err(EngineError::MissingVariable(name.into())),
+            },
+            Command::Min | Command::Max => {
+                let rhs = self.pop()?;
+                let lhs = self.pop()?;
+
+                match (&lhs, &rhs) {
+                    (Type::String | Type::Any, Type::String | Type::Any) => {}
+                    _ if numeric_type(&lhs, &rhs).is_some() => {}
+                    _ => return Err(EngineError::MismatchType),
+                }
+                self.stack.push(join(lhs, rhs));
+            }
+            Command::Pow => match (self.pop()?, self.pop()?) {
+                (Type::Int | Type::Any, Type::Int | Type::Any) => self.stack.push(Type::Int),
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Abs => match self.pop()? {
+                Type::Int | Type::Any => self.stack.push(Type::Int),
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Trim => match self.pop()? {
+                Type::String | Type::Any => self.stack.push(Type::String),
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Replace => match (self.pop()?, self.pop()?, self.pop()?) {
+                (Type::String | Type::Any, Type::String | Type::Any, Type::String | Type::Any) => {
+                    self.stack.push(Type::String)
+                }
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Join => match (self.pop()?, self.pop()?) {
+                (Type::String | Type::Any, Type::List | Type::Any) => self.stack.push(Type::String),
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Nip => {
+                let top = self.pop()?;
+                self.pop()?;
+                self.stack.push(top);
+            }
+            Command::Tuck => {
+                let top = self.pop()?;
+                let below = self.pop()?;
+                self.stack.push(top.clone());
+                self.stack.push(below);
+                self.stack.push(top);
+            }
+            Command::TwoDup => {
+                let below = self.peek(1)?;
+                let top = self.peek(0)?;
+                self.stack.push(below);
+                self.stack.push(top);
+            }
+            Command::Caesar(_) => match self.pop()? {
+                Type::String | Type::Any => self.stack.push(Type::String),
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Format(count) => {
+                match self.pop()? {
+                    Type::String | Type::Any => {}
+                    _ => return Err(EngineError::MismatchType),
+                }
+                let len = self.stack.len();
+                if len < *count {
+                    return Err(EngineError::EmptyStack);
+                }
+                self.stack.truncate(len - count);
+                self.stack.push(Type::String);
+            }
+            Command::Assert => match self.pop()? {
+                Type::Bool | Type::Any => {}
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::AssertEq => {
+                self.pop()?;
+                self.pop()?;
+            }
+            Command::Concat => {
+                self.pop()?;
+                self.pop()?;
+                self.stack.push(Type::String);
+            }
+            Command::Count => match (self.pop()?, self.pop()?) {
+                (_, Type::List | Type::Any) => self.stack.push(Type::Int),
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Slice => match (self.pop()?, self.pop()?, self.pop()?) {
+                (Type::Int | Type::Any, Type::Int | Type::Any, Type::List | Type::Any) => {
+                    self.stack.push(Type::List)
+                }
+                _ => return Err(EngineError::MismatchType),
+            },
+        }
+        Ok(true)
+    }
+
+    // Combines the state from another path through the program into this
+    // one. Both must have the same number of values on the stack and the
+    // same number of scopes open.
+    fn merge(&mut self, other: Typechecker) {
+        let stack = std::mem::take(&mut self.stack);
+        self.stack = stack
+            .into_iter()
+            .zip(other.stack)
+            .map(|(a, b)| join(a, b))
+            .collect();
+        for (frame, other) in self.vars.iter_mut().zip(other.vars) {
+            for (name, ty) in other {
+                let ty = match frame.remove(&name) {
+                    Some(existing) => join(existing, ty),
+                    None => ty,
+                };
+                frame.insert(name, ty);
+            }
+        }
+    }
+
+    // Returns false if the checker stopped following the program partway
+    fn typecheck_block(
+        &mut self,
+        commands: &[Command],
+        output: &mut Type,
+    ) -> Result<bool, EngineError> {
+        // Errors point at the failing command the same way evaluation does
+        for (index, command) in commands.iter().enumerate() {
+            let followed = self.typecheck_command(command, output).map_err(|source| {
+                EngineError::AtCommand {
+                    index,
+                    command: command.to_string(),
+                    source: Box::new(source),
+                }
+            })?;
+            if !followed {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn typecheck(&mut self, commands: &[Command]) -> Result<Type, EngineError> {
+        let mut output = Type::Nothing;
+        if self.typecheck_block(commands, &mut output)? {
+            Ok(output)
+        } else {
+            Ok(Type::Any)
+        }
+    }
+}
+
+// Renders a value as JSON text
+fn to_json(value: &Value) -> String {
+    match value {
+        Value::Nothing => "null".into(),
+        Value::Int(i) => i.to_string(),
+        // JSON has no NaN or infinity
+        Value::Float(f) if !f.is_finite() => "null".into(),
+        Value::Float(f) => format!("{:?}", f),
+        Value::Bool(b) => b.to_string(),
+        Value::String(s) => {
+            let mut output = String::from("\"");
+            for c in s.chars() {
+                match c {
+                    '"' => output.push_str("\\\""),
+                    '\\' => output.push_str("\\\\"),
+                    '\n' => output.push_str("\\n"),
+                    '\r' => output.push_str("\\r"),
+                    '\t' => output.push_str("\\t"),
+                    c if (c as u32) < 0x20 => output.push_str(&format!("\\u{:04x}", c as u32)),
+                    c => output.push(c),
+                }
+            }
+            output.push('"');
+            output
+        }
+        Value::List(items) => {
+            let items: Vec<_> = items.iter().map(to_json).collect();
+            format!("[{}]", items.join(","))
+        }
+    }
+}
+
+// Command line flags shared by file and REPL mode
+struct Options {
+    typecheck: bool,
+    json: bool,
+    trace: bool,
+    // Only typecheck, without running anything
+    check: bool,
+}
+
+fn format_answer(answer: &Value, options: &Options) -> String {
+    if options.json {
+        to_json(answer)
+    } else {
+        answer.to_string()
+    }
+}
+
+// Parses and evaluates a program, rejecting it first if it doesn't typecheck
+fn run(engine: &mut Evaluator, contents: &str, typecheck: bool) -> Result<Value, EngineError> {
+    let commands = parse(contents)?;
+
+    if typecheck {
+        Typechecker::for_evaluator(engine).typecheck(&commands)?;
+    }
+
+    engine.evaluate(&commands)
+}
+
+// Reads a program from a file, or from stdin when the name is `-`
+fn read_source(name: &str, stdin: &mut dyn Read) -> std::io::Result<String> {
+    if name == "-" {
+        let mut contents = String::new();
+        stdin.read_to_string(&mut contents)?;
+        Ok(contents)
+    } else {
+        std::fs::read_to_string(name)
+    }
+}
+
+// Reads and runs a single program, given its file name
+fn run_file(name: &str, options: &Options, stdin: &mut dyn Read) -> Result<Value, EngineError> {
+    let contents =
+        read_source(name, stdin).map_err(|err| EngineError::Io(format!("{}: {}", name, err)))?;
+
+    run_program(&contents, options)
+}
+
+// Parses and typechecks a program without evaluating it
+fn check_program(contents: &str) -> Result<(), EngineError> {
+    let commands = parse(contents)?;
+    Typechecker::new().typecheck(&commands)?;
+    Ok(())
+}
+
+fn run_program(contents: &str, options: &Options) -> Result<Value, EngineError> {
+    if options.check {
+        check_program(contents)?;
+        return Ok(Value::Nothing);
+    }
+
+    let mut engine = Evaluator::new();
+    engine.trace = options.trace;
+    run(&mut engine, contents, options.typecheck)
+}
+
+// A program named on the command line
+#[derive(Debug, PartialEq)]
+enum Source {
+    File(String),
+    // Given directly with `--eval`
+    Inline(String),
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<(Options, Vec<Source>), String> {
+    let mut options = Options {
+        typecheck: true,
+        json: false,
+        trace: false,
+        check: false,
+    };
+    let mut sources = vec![];
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--no-typecheck" => options.typecheck = false,
+            "--json" => options.json = true,
+            "--trace" => options.trace = true,
+            "--check" => options.check = true,
+            "--eval" => match args.next() {
+                Some(program) => sources.push(Source::Inline(program)),
+                None => return Err("--eval needs a program to run".into()),
+            },
+            _ => sources.push(Source::File(arg)),
+        }
+    }
+
+    // The REPL would run each line, so checking with nothing named checks
+    // stdin as one program instead
+    if options.check && sources.is_empty() {
+        sources.push(Source::File("-".into()));
+    }
+
+    Ok((options, sources))
+}
+
+// Reads and runs one line at a time, keeping variables and the stack around
+// between lines
+fn repl(options: &Options) {
+    let mut engine = Evaluator::new();
+    engine.trace = options.trace;
+    let stdin = std::io::stdin();
+
+    loop {
+        print!("> ");
+        let _ = std::io::stdout().flush();
+
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(err) => {
+                eprintln!("error: {}", err);
+                break;
+            }
+        }
+
+        match run(&mut engine, &line, options.typecheck) {
+            Ok(Value::Nothing) => {}
+            Ok(answer) => println!("{}", format_answer(&answer, options)),
+            // Every line is its own one-line program, so its line number
+            // isn't useful
+            Err(EngineError::AtLine { source, .. }) => eprintln!("error: {}", source),
+            Err(err) => eprintln!("error: {}", err),
+        }
+    }
+}
+
+#[test]
+fn test1() -> Result<(), EngineError> {
+    let commands = vec![
+        Command::SetVar("a".into(), Value::Int(100)),
+        Command::GetVar("a".into()),
+    ];
+
+    let mut evaluator = Evaluator::new();
+
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(100));
+
+    Ok(())
+}
+
+#[test]
+fn eval_set_get() -> Result<(), EngineError> {
+    let input = "set x 30\nget x";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(30));
+
+    Ok(())
+}
+
+#[test]
+fn eval_set_get_string() -> Result<(), EngineError> {
+    let input = "set x \"hello\"\nget x";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("hello".into()));
+
+    Ok(())
+}
+
+#[test]
+fn eval_stack() -> Result<(), EngineError> {
+    let input = "push 100\npush 30\nadd\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(130));
+
+    Ok(())
+}
+
+#[test]
+fn eval_pushvar() -> Result<(), EngineError> {
+    let input = "set x 33\npushvar x\npush 100\nadd\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(133));
+
+    Ok(())
+}
+
+#[test]
+fn eval_sub() -> Result<(), EngineError> {
+    let input = "push 100\npush 30\nsub\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(70));
+
+    Ok(())
+}
+
+#[test]
+fn eval_sub_string() -> Result<(), EngineError> {
+    let input = "push \"a\"\npush \"b\"\nsub\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_mul() -> Result<(), EngineError> {
+    let input = "push 6\npush 7\nmul\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(42));
+
+    Ok(())
+}
+
+#[test]
+fn eval_mul_string() -> Result<(), EngineError> {
+    let input = "push \"ab\"\npush 3\nmul\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("ababab".into()));
+
+    Ok(())
+}
+
+#[test]
+fn eval_mul_string_negative() -> Result<(), EngineError> {
+    let input = "push \"ab\"\npush -2\nmul\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("".into()));
+
+    Ok(())
+}
+
+#[test]
+fn eval_mul_string_too_long() -> Result<(), EngineError> {
+    let input = "push \"a\"\npush 9223372036854775807\nmul\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::Overflow)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_mul_two_strings() -> Result<(), EngineError> {
+    let input = "push \"ab\"\npush \"cd\"\nmul\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_div() -> Result<(), EngineError> {
+    let input = "push 100\npush 7\ndiv\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(14));
+
+    Ok(())
+}
+
+#[test]
+fn eval_div_by_zero() -> Result<(), EngineError> {
+    let input = "push 10\npush 0\ndiv";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::DivideByZero)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_div_string() -> Result<(), EngineError> {
+    let input = "push \"a\"\npush 2\ndiv";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_mod() -> Result<(), EngineError> {
+    let input = "push 10\npush 3\nmod\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(1));
+
+    Ok(())
+}
+
+#[test]
+fn eval_mod_by_zero() -> Result<(), EngineError> {
+    let input = "push 10\npush 0\nmod";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::DivideByZero)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_mod_string() -> Result<(), EngineError> {
+    let input = "push \"a\"\npush 3\nmod";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_neg() -> Result<(), EngineError> {
+    let input = "push 5\nneg\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(-5));
+
+    Ok(())
+}
+
+#[test]
+fn parse_neg_extra_params() {
+    let result = parse("push 5\nneg 5");
+
+    assert!(matches!(
+        result,
+        Err(EngineError::AtLine { span: Span { line: 2, .. }, source }) if matches!(*source, EngineError::MismatchNumParams)
+    ));
+}
+
+#[test]
+fn eval_add_float() -> Result<(), EngineError> {
+    let input = "push 1.5\npush 2.5\nadd\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Float(4.0));
+
+    Ok(())
+}
+
+#[test]
+fn eval_add_int_float() -> Result<(), EngineError> {
+    let input = "push 1\npush 0.5\nadd\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Float(1.5));
+
+    Ok(())
+}
+
+#[test]
+fn eval_sub_float() -> Result<(), EngineError> {
+    let input = "push 3.5\npush 1\nsub\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Float(2.5));
+
+    Ok(())
+}
+
+#[test]
+fn parse_bad_float() {
+    let result = parse("push 1.2.3");
+
+    assert!(matches!(
+        result,
+        Err(EngineError::AtLine { span: Span { line: 1, .. }, source })
+            if matches!(*source, EngineError::TypeMismatch { expected: Type::Float, .. })
+    ));
+}
+
+#[test]
+fn eval_set_get_bool() -> Result<(), EngineError> {
+    let input = "set flag true\nget flag";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Bool(true));
+
+    Ok(())
+}
+
+#[test]
+fn eval_push_false() -> Result<(), EngineError> {
+    let input = "push false\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Bool(false));
+
+    Ok(())
+}
+
+#[test]
+fn eval_bool_literal_not_shadowed() -> Result<(), EngineError> {
+    let input = "set true 5\nset x true\nget x";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Bool(true));
+
+    Ok(())
+}
+
+#[test]
+fn eval_lt() -> Result<(), EngineError> {
+    let input = "push 3\npush 5\nlt\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Bool(true));
+
+    Ok(())
+}
+
+#[test]
+fn eval_ge_equal() -> Result<(), EngineError> {
+    let input = "push 5\npush 5\nge\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Bool(true));
+
+    Ok(())
+}
+
+#[test]
+fn eval_gt_string() -> Result<(), EngineError> {
+    let input = "push \"apple\"\npush \"banana\"\ngt\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Bool(false));
+
+    Ok(())
+}
+
+#[test]
+fn eval_eq_mismatched_types() -> Result<(), EngineError> {
+    let input = "push 1\npush \"1\"\neq\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Bool(false));
+
+    Ok(())
+}
+
+#[test]
+fn eval_le_bool() -> Result<(), EngineError> {
+    let input = "push true\npush false\nle";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_and() -> Result<(), EngineError> {
+    let input = "push true\npush false\nand\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Bool(false));
+
+    Ok(())
+}
+
+#[test]
+fn eval_or() -> Result<(), EngineError> {
+    let input = "push true\npush false\nor\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Bool(true));
+
+    Ok(())
+}
+
+#[test]
+fn eval_not() -> Result<(), EngineError> {
+    let input = "push false\nnot\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Bool(true));
+
+    Ok(())
+}
+
+#[test]
+fn eval_and_int() -> Result<(), EngineError> {
+    let input = "push true\npush 1\nand";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_dup() -> Result<(), EngineError> {
+    let input = "push 5\ndup\nadd\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(10));
+
+    Ok(())
+}
+
+#[test]
+fn eval_dup_empty() -> Result<(), EngineError> {
+    let input = "dup";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::EmptyStack)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_swap() -> Result<(), EngineError> {
+    let input = "push 10\npush 3\nswap\nsub\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(-7));
+
+    Ok(())
+}
+
+#[test]
+fn eval_swap_one_value() -> Result<(), EngineError> {
+    let input = "push 10\nswap";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::EmptyStack)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_over() -> Result<(), EngineError> {
+    let input = "push 1\npush 2\nover";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    evaluator.evaluate(&commands)?;
+
+    assert_eq!(
+        evaluator.stack,
+        vec![Value::Int(1), Value::Int(2), Value::Int(1)]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn eval_rot() -> Result<(), EngineError> {
+    let input = "push 1\npush 2\npush 3\nrot";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    evaluator.evaluate(&commands)?;
+
+    assert_eq!(
+        evaluator.stack,
+        vec![Value::Int(2), Value::Int(3), Value::Int(1)]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn eval_rot_two_values() -> Result<(), EngineError> {
+    let input = "push 1\npush 2\nrot";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::EmptyStack)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_drop() -> Result<(), EngineError> {
+    let input = "push 1\npop\npush 2\ndrop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(1));
+    assert!(evaluator.stack.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn eval_drop_empty() -> Result<(), EngineError> {
+    let input = "drop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::EmptyStack)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_clear() -> Result<(), EngineError> {
+    let input = "push 1\npush 2\npush 3\nclear\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result,
+        Err(EngineError::AtCommand { index: 4, source, .. })
+            if matches!(*source, EngineError::EmptyStack)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_clear_empty() -> Result<(), EngineError> {
+    let input = "push 1\npop\nclear";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(1));
+
+    Ok(())
+}
+
+#[test]
+fn eval_depth() -> Result<(), EngineError> {
+    let input = "push 1\npush 2\ndepth\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(2));
+
+    Ok(())
+}
+
+#[test]
+fn eval_print() -> Result<(), EngineError> {
+    let input = "push 42\nprint\npop";
+
+    let commands = parse(input)?;
+
+    let buffer = Rc::new(RefCell::new(Vec::new()));
+    let mut evaluator = Evaluator::with_writer(buffer.clone());
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(42));
+    assert_eq!(buffer.borrow().as_slice(), b"42\n");
+
+    Ok(())
+}
+
+#[test]
+fn eval_print_empty() -> Result<(), EngineError> {
+    let input = "print";
+
+    let commands = parse(input)?;
+
+    let buffer = Rc::new(RefCell::new(Vec::new()));
+    let mut evaluator = Evaluator::with_writer(buffer.clone());
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::EmptyStack)
+    ));
+    assert!(buffer.borrow().is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn parse_full_line_comment() -> Result<(), EngineError> {
+    let input = "# add two numbers\npush 1\npush 2\nadd\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(3));
+
+    Ok(())
+}
+
+#[test]
+fn parse_args_eval() -> Result<(), EngineError> {
+    let args = vec![
+        "--json",
+        "--eval",
+        "push 1\npush 2\nadd\npop",
+        "file.onehour",
+    ];
+
+    let (options, sources) = parse_args(args.into_iter().map(String::from)).unwrap();
+
+    assert!(options.json);
+    assert!(options.typecheck);
+    assert_eq!(
+        sources,
+        vec![
+            Source::Inline("push 1\npush 2\nadd\npop".into()),
+            Source::File("file.onehour".into()),
+        ]
+    );
+
+    match &sources[0] {
+        Source::Inline(program) => assert_eq!(run_program(program, &options)?, Value::Int(3)),
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn parse_args_eval_missing_program() {
+    let args = vec!["--eval".to_string()];
+
+    assert!(parse_args(args).is_err());
+}
+
+#[test]
+fn parse_trailing_comment() -> Result<(), EngineError> {
+    let input = "push 5  # the answer\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(5));
+
+    Ok(())
+}
+
+#[test]
+fn parse_hash_in_string() -> Result<(), EngineError> {
+    let input = "set x \"a#b\" # not part of the string\nget x";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("a#b".into()));
+
+    Ok(())
+}
+
+#[test]
+fn tokenize_quoted_string() {
+    let tokens = tokenize("set greeting \"hello world\"");
+
+    assert_eq!(
+        tokens,
+        vec![
+            (1, "set".into()),
+            (5, "greeting".into()),
+            (14, "\"hello world\"".into())
+        ]
+    );
+}
+
+#[test]
+fn eval_set_get_multi_word_string() -> Result<(), EngineError> {
+    let input = "set greeting \"hello world\"\nget greeting";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("hello world".into()));
+
+    Ok(())
+}
+
+#[test]
+fn eval_string_escapes() -> Result<(), EngineError> {
+    let input = r#"push "a\nb\tc\\d\"e"
+pop"#;
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("a\nb\tc\\d\"e".into()));
+
+    Ok(())
+}
+
+#[test]
+fn parse_escaped_quote_with_spaces() -> Result<(), EngineError> {
+    let input = r#"set x "say \"hi there\"" # comment
+get x"#;
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("say \"hi there\"".into()));
+
+    Ok(())
+}
+
+#[test]
+fn parse_bad_escape() {
+    let result = parse(r#"push "a\qb""#);
+
+    assert!(matches!(
+        result,
+        Err(EngineError::AtLine { span: Span { line: 1, .. }, source }) if matches!(*source, EngineError::BadEscape('q'))
+    ));
+}
+
+#[test]
+fn eval_push_negative_int() -> Result<(), EngineError> {
+    let input = "push -5 # negative\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(-5));
+
+    Ok(())
+}
+
+#[test]
+fn parse_negative_value() -> Result<(), EngineError> {
+    assert_eq!(parse_value("-5")?, Value::Int(-5));
+    assert_eq!(parse_value("-2.5")?, Value::Float(-2.5));
+    assert_eq!(parse_value("\"-5\"")?, Value::String("-5".into()));
+
+    Ok(())
+}
+
+#[test]
+fn eval_push_hex() -> Result<(), EngineError> {
+    let input = "push 0xff\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(255));
+
+    Ok(())
+}
+
+#[test]
+fn parse_radix_literals() -> Result<(), EngineError> {
+    assert_eq!(parse_int("0o17")?, Value::Int(15));
+    assert_eq!(parse_int("0b1010")?, Value::Int(10));
+    assert_eq!(parse_int("-0x10")?, Value::Int(-16));
+
+    Ok(())
+}
+
+#[test]
+fn parse_bad_hex() {
+    for input in &["0xZZ", "0b102", "0x-5"] {
+        assert!(matches!(
+            parse_int(input),
+            Err(EngineError::TypeMismatch {
+                expected: Type::Int,
+                ..
+            })
+        ));
+    }
+}
+
+#[test]
+fn eval_push_underscores() -> Result<(), EngineError> {
+    let input = "push 1_000\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(1000));
+
+    Ok(())
+}
+
+#[test]
+fn parse_underscores() -> Result<(), EngineError> {
+    assert_eq!(parse_int("1_000_000")?, Value::Int(1_000_000));
+    assert_eq!(parse_int("0xff_ff")?, Value::Int(0xffff));
+
+    Ok(())
+}
+
+#[test]
+fn parse_bad_underscores() {
+    for input in &["push _5", "push 5_", "push 1__0"] {
+        assert!(matches!(
+            parse(input),
+            Err(EngineError::AtLine { span: Span { line: 1, .. }, source })
+                if matches!(*source, EngineError::TypeMismatch { expected: Type::Int, .. })
+        ));
+    }
+}
+
+#[test]
+fn eval_semicolons() -> Result<(), EngineError> {
+    let single_line = parse("push 1; push 2; add; pop")?;
+    let multi_line = parse("push 1\npush 2\nadd\npop")?;
+
+    let result = Evaluator::new().evaluate(&single_line)?;
+    let expected = Evaluator::new().evaluate(&multi_line)?;
+
+    assert_eq!(result, expected);
+    assert_eq!(result, Value::Int(3));
+
+    Ok(())
+}
+
+#[test]
+fn parse_semicolon_in_string() -> Result<(), EngineError> {
+    let input = "set x \"a;b\";get x";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("a;b".into()));
+
+    Ok(())
+}
+
+#[test]
+fn display_engine_error() {
+    assert_eq!(
+        EngineError::UnknownCommand("foo".into()).to_string(),
+        "unknown command: foo"
+    );
+    assert_eq!(
+        EngineError::MissingVariable("a".into()).to_string(),
+        "missing variable: a"
+    );
+    assert_eq!(
+        EngineError::BadEscape('q').to_string(),
+        "invalid escape sequence: \\q"
+    );
+}
+
+#[test]
+fn engine_error_is_error() {
+    let err: Box<dyn std::error::Error> = Box::new(EngineError::DivideByZero);
+
+    assert_eq!(err.to_string(), "division by zero");
+}
+
+#[test]
+fn parse_error_line_number() {
+    let result = parse("push 1\nfrobnicate\npop");
+
+    match result {
+        Err(EngineError::AtLine { span, source }) => {
+            assert_eq!(span, Span { line: 2, col: 1 });
+            assert!(matches!(*source, EngineError::UnknownCommand(name) if name == "frobnicate"));
+        }
+        _ => panic!("expected an error on line 2"),
+    }
+}
+
+#[test]
+fn parse_error_column() {
+    let result = parse("push 1\nset a 1\n  push 12x; pop");
+
+    match result {
+        Err(EngineError::AtLine { span, source }) => {
+            assert_eq!(span, Span { line: 3, col: 8 });
+            assert!(matches!(*source, EngineError::TypeMismatch { .. }));
+        }
+        _ => panic!("expected an error on line 3"),
+    }
+
+    let result = parse("push 1; frobnicate 2");
+
+    assert!(matches!(
+        result,
+        Err(EngineError::AtLine {
+            span: Span { line: 1, col: 9 },
+            ..
+        })
+    ));
+}
+
+#[test]
+fn display_error_line_number() {
+    let err = EngineError::AtLine {
+        span: Span { line: 3, col: 5 },
+        source: Box::new(EngineError::MismatchNumParams),
+    };
+
+    assert_eq!(
+        err.to_string(),
+        "line 3, column 5: wrong number of parameters"
+    );
+}
+
+#[test]
+fn eval_add_type_mismatch() -> Result<(), EngineError> {
+    let input = "push 1\npush \"a\"\nadd";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::TypeMismatch {
+            expected: Type::String,
+            found: Type::Int,
+        })
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn display_type_mismatch() {
+    let err = EngineError::TypeMismatch {
+        expected: Type::Int,
+        found: Type::String,
+    };
+
+    assert_eq!(err.to_string(), "type mismatch: expected int, found string");
+}
+
+#[test]
+fn typecheck_set_get() -> Result<(), EngineError> {
+    let commands = parse("set a 1\nget a")?;
+
+    let mut typechecker = Typechecker::new();
+    let result = typechecker.typecheck(&commands)?;
+
+    assert_eq!(result, Type::Int);
+
+    Ok(())
+}
+
+#[test]
+fn typecheck_missing_variable() -> Result<(), EngineError> {
+    let commands = parse("get b")?;
+
+    let mut typechecker = Typechecker::new();
+    let result = typechecker.typecheck(&commands);
+
+    assert!(
+        matches!(result.map_err(EngineError::root), Err(EngineError::MissingVariable(name)) if name == "b")
+    );
+
+    Ok(())
+}
+
+#[test]
+fn typecheck_stack() -> Result<(), EngineError> {
+    let commands = parse("set x 2\npushvar x\npush 1.5\nadd\ndup\npush 3\nlt\ndrop\npop")?;
+
+    let mut typechecker = Typechecker::new();
+    let result = typechecker.typecheck(&commands)?;
+
+    assert_eq!(result, Type::Float);
+
+    Ok(())
+}
+
+#[test]
+fn typecheck_add_mismatch() -> Result<(), EngineError> {
+    let commands = parse("push 1\npush \"a\"\nadd\npop")?;
+
+    let mut typechecker = Typechecker::new();
+    let result = typechecker.typecheck(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::TypeMismatch {
+            expected: Type::String,
+            found: Type::Int,
+        })
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn typecheck_empty_stack() -> Result<(), EngineError> {
+    let commands = parse("push 1\nadd")?;
+
+    let mut typechecker = Typechecker::new();
+    let result = typechecker.typecheck(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::EmptyStack)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn run_has_var_fallback() -> Result<(), EngineError> {
+    let input = "has cfg; if; get cfg; else; push \"default\"; pop; end";
+
+    let mut evaluator = Evaluator::new();
+    let result = run(&mut evaluator, input, true)?;
+
+    assert_eq!(result, Value::String("default".into()));
+
+    Ok(())
+}
+
+#[test]
+fn run_type_error_at_command() {
+    let mut evaluator = Evaluator::new();
+    let result = run(&mut evaluator, "push 5; assert", true);
+
+    assert!(matches!(
+        result,
+        Err(EngineError::AtCommand { index: 1, ref command, .. }) if command == "assert"
+    ));
+    assert_eq!(
+        result.unwrap_err().to_string(),
+        "command 1 (assert): mismatched types"
+    );
+}
+
+#[test]
+fn run_rejects_ill_typed() {
+    let input = "set x 1\npush 1\npush \"a\"\nadd\npop";
+
+    let mut evaluator = Evaluator::new();
+    let result = run(&mut evaluator, input, true);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::TypeMismatch { .. })
+    ));
+    // Nothing was evaluated
+    assert!(evaluator.all_vars().is_empty());
+}
+
+#[test]
+fn run_without_typecheck() {
+    let input = "set x 1\npush 1\npush \"a\"\nadd\npop";
+
+    let mut evaluator = Evaluator::new();
+    let result = run(&mut evaluator, input, false);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::TypeMismatch { .. })
+    ));
+    assert_eq!(evaluator.get_var("x"), Some(&Value::Int(1)));
+}
+
+#[test]
+fn run_traces_source_commands() -> Result<(), EngineError> {
+    let buffer = Rc::new(RefCell::new(Vec::new()));
+    let mut evaluator = Evaluator::with_writer(buffer.clone());
+    evaluator.trace = true;
+
+    run(&mut evaluator, "push 1\npush 2\nadd\npop", true)?;
+
+    let log = String::from_utf8(buffer.borrow().clone()).unwrap();
+
+    assert_eq!(
+        log,
+        "push 1 -> [1]\npush 2 -> [1, 2]\nadd -> [3]\npop -> []\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn run_keeps_state_between_calls() -> Result<(), EngineError> {
+    let mut evaluator = Evaluator::new();
+
+    run(&mut evaluator, "set x 5", true)?;
+    run(&mut evaluator, "push 1", true)?;
+    let result = run(&mut evaluator, "pushvar x\nadd\npop", true)?;
+
+    assert_eq!(result, Value::Int(6));
+
+    Ok(())
+}
+
+#[test]
+fn read_source_from_stdin() -> std::io::Result<()> {
+    let mut stdin: &[u8] = b"push 1\npop";
+
+    let contents = read_source("-", &mut stdin)?;
+
+    assert_eq!(contents, "push 1\npop");
+
+    Ok(())
+}
+
+#[test]
+fn read_source_from_file() -> std::io::Result<()> {
+    let mut stdin: &[u8] = b"not this";
+
+    let contents = read_source("samples/hello.onehour", &mut stdin)?;
+
+    assert_eq!(contents, std::fs::read_to_string("samples/hello.onehour")?);
+
+    Ok(())
+}
+
+#[test]
+fn run_file_missing() {
+    let options = Options {
+        typecheck: true,
+        json: false,
+        trace: false,
+        check: false,
+    };
+    let mut stdin: &[u8] = b"";
+
+    let result = run_file("samples/does-not-exist.onehour", &options, &mut stdin);
+
+    assert!(matches!(
+        result,
+        Err(EngineError::Io(message)) if message.starts_with("samples/does-not-exist.onehour: ")
+    ));
+}
+
+#[test]
+fn run_file_from_stdin() -> Result<(), EngineError> {
+    let options = Options {
+        typecheck: true,
+        json: false,
+        trace: false,
+        check: false,
+    };
+    let mut stdin: &[u8] = b"push 1\npush 2\nadd\npop";
+
+    let result = run_file("-", &options, &mut stdin)?;
+
+    assert_eq!(result, Value::Int(3));
+
+    Ok(())
+}
+
+#[test]
+fn run_check_only() -> Result<(), EngineError> {
+    let (options, _) = parse_args(vec!["--check".to_string()]).unwrap();
+
+    assert!(options.check);
+
+    // Well typed, so this passes even though running it would fail
+    let input = "push 1\npush 0\ndiv\npop";
+
+    assert_eq!(run_program(input, &options)?, Value::Nothing);
+
+    let input = "push 1\npush \"a\"\nadd\npop";
+
+    let result = run_program(input, &options);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::TypeMismatch { .. })
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn run_check_stdin() -> Result<(), EngineError> {
+    let (options, sources) = parse_args(vec!["--check".to_string()]).unwrap();
+
+    assert_eq!(sources, vec![Source::File("-".into())]);
+
+    // Would fail if it were evaluated
+    let mut stdin: &[u8] = b"push \"x\"\nprint\npush 1\npush 0\ndiv\npop";
+    let result = run_file("-", &options, &mut stdin)?;
+
+    assert_eq!(result, Value::Nothing);
+
+    Ok(())
+}
+
+#[test]
+fn eval_del() -> Result<(), EngineError> {
+    let input = "set a 1\ndel a\nget a";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(
+        matches!(result.map_err(EngineError::root), Err(EngineError::MissingVariable(name)) if name == "a")
+    );
+
+    Ok(())
+}
+
+#[test]
+fn eval_del_missing() -> Result<(), EngineError> {
+    let input = "del nope";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(
+        matches!(result.map_err(EngineError::root), Err(EngineError::MissingVariable(name)) if name == "nope")
+    );
+
+    Ok(())
+}
+
+#[test]
+fn eval_makelist() -> Result<(), EngineError> {
+    let input = "push 0\npush 1\npush 2\npush 3\nmakelist 3\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(
+        result,
+        Value::List(Rc::new(vec![Value::Int(1), Value::Int(2), Value::Int(3)]))
+    );
+    assert_eq!(evaluator.stack, vec![Value::Int(0)]);
+
+    Ok(())
+}
+
+#[test]
+fn eval_makelist_too_few() -> Result<(), EngineError> {
+    let input = "push 1\nmakelist 2";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::EmptyStack)
+    ));
+    assert_eq!(evaluator.stack, vec![Value::Int(1)]);
+
+    Ok(())
+}
+
+#[test]
+fn eval_index() -> Result<(), EngineError> {
+    let input = "push 10\npush 20\npush 30\nmakelist 3\npush 1\nindex\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(20));
+
+    Ok(())
+}
+
+#[test]
+fn eval_index_out_of_bounds() -> Result<(), EngineError> {
+    for index in &["3", "-1"] {
+        let input = format!(
+            "push 10\npush 20\npush 30\nmakelist 3\npush {}\nindex",
+            index
+        );
+
+        let commands = parse(&input)?;
+
+        let mut evaluator = Evaluator::new();
+        let result = evaluator.evaluate(&commands);
+
+        assert!(matches!(
+            result.map_err(EngineError::root),
+            Err(EngineError::IndexOutOfBounds)
+        ));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn eval_len() -> Result<(), EngineError> {
+    let input = "push 1\npush 2\nmakelist 2\nlen\npush \"héllo\"\nlen\nadd\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(7));
+
+    Ok(())
+}
+
+#[test]
+fn typecheck_index_any() -> Result<(), EngineError> {
+    let commands = parse("push 1\nmakelist 1\npush 0\nindex\npush 2\nadd\npop")?;
+
+    let mut typechecker = Typechecker::new();
+    let result = typechecker.typecheck(&commands)?;
+
+    assert_eq!(result, Type::Any);
+
+    Ok(())
+}
+
+#[test]
+fn eval_append() -> Result<(), EngineError> {
+    let input = "push 1\npush 2\nmakelist 2\npush \"three\"\nappend\ndup\nlen\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(3));
+
+    let commands = parse("push 2\nindex\npop")?;
+
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("three".into()));
+
+    Ok(())
+}
+
+#[test]
+fn eval_append_not_list() -> Result<(), EngineError> {
+    let input = "push 1\npush 2\nappend";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_pushvar_copy_on_write() -> Result<(), EngineError> {
+    let stored = Value::List(Rc::new(vec![Value::Int(1)]));
+
+    let mut evaluator = Evaluator::new();
+    evaluator.set_var("xs", stored.clone());
+
+    let commands = parse("pushvar xs\npush 2\nappend\npop")?;
+
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(
+        result,
+        Value::List(Rc::new(vec![Value::Int(1), Value::Int(2)]))
+    );
+    assert_eq!(evaluator.get_var("xs"), Some(&stored));
+
+    Ok(())
+}
+
+#[test]
+fn eval_pushvar_shares_string() -> Result<(), EngineError> {
+    let mut evaluator = Evaluator::new();
+
+    let commands = parse("set s \"hello\"\npushvar s")?;
+
+    evaluator.evaluate(&commands)?;
+
+    match (evaluator.get_var("s"), evaluator.stack.last()) {
+        (Some(Value::String(stored)), Some(Value::String(pushed))) => {
+            assert!(Rc::ptr_eq(stored, pushed))
+        }
+        _ => panic!("expected the string on the stack"),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn eval_upper() -> Result<(), EngineError> {
+    let input = "push \"Abc\"\nupper\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("ABC".into()));
+
+    Ok(())
+}
+
+#[test]
+fn eval_lower_multi_byte() -> Result<(), EngineError> {
+    let input = "push \"ÉCOLE\"\nlower\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("école".into()));
+
+    Ok(())
+}
+
+#[test]
+fn eval_upper_not_string() -> Result<(), EngineError> {
+    let input = "push 1\nupper";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_substr() -> Result<(), EngineError> {
+    let input = "push \"hello world\"\npush 6\npush 5\nsubstr\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("world".into()));
+
+    Ok(())
+}
+
+#[test]
+fn eval_substr_out_of_range() -> Result<(), EngineError> {
+    for (start, len) in &[(3, 3), (-1, 2), (0, -1)] {
+        let input = format!("push \"hello\"\npush {}\npush {}\nsubstr", start, len);
+
+        let commands = parse(&input)?;
+
+        let mut evaluator = Evaluator::new();
+        let result = evaluator.evaluate(&commands);
+
+        assert!(matches!(
+            result.map_err(EngineError::root),
+            Err(EngineError::IndexOutOfBounds)
+        ));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn eval_substr_multi_byte() -> Result<(), EngineError> {
+    let input = "push \"naïve café\"\npush 2\npush 3\nsubstr\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("ïve".into()));
+
+    Ok(())
+}
+
+#[test]
+fn eval_split() -> Result<(), EngineError> {
+    let input = "push \"a,b,c\"\npush \",\"\nsplit\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(
+        result,
+        Value::List(Rc::new(vec![
+            Value::String("a".into()),
+            Value::String("b".into()),
+            Value::String("c".into()),
+        ]))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn eval_split_chars() -> Result<(), EngineError> {
+    let input = "push \"hé!\"\npush \"\"\nsplit\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(
+        result,
+        Value::List(Rc::new(vec![
+            Value::String("h".into()),
+            Value::String("é".into()),
+            Value::String("!".into()),
+        ]))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn eval_split_not_string() -> Result<(), EngineError> {
+    let input = "push \"a,b\"\npush 1\nsplit";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_set_get_none() -> Result<(), EngineError> {
+    let input = "set x 1\nset x none\nget x";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Nothing);
+    assert_eq!(evaluator.get_var("x"), Some(&Value::Nothing));
+
+    Ok(())
+}
+
+#[test]
+fn eval_arithmetic_on_none() -> Result<(), EngineError> {
+    let commands = parse("push 1\npush none\nsub")?;
+
+    let result = Evaluator::new().evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    let commands = parse("push 1\npush none\nadd")?;
+
+    let result = Evaluator::new().evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::TypeMismatch {
+            found: Type::Nothing,
+            ..
+        })
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_add_overflow() -> Result<(), EngineError> {
+    let input = "push 9223372036854775807\npush 1\nadd";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::Overflow)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_checked_arithmetic() -> Result<(), EngineError> {
+    let min = i64::MIN;
+    let programs = vec![
+        format!("push {}\npush 1\nsub", min),
+        format!("push {}\npush 2\nmul", i64::MAX),
+        format!("push {}\npush -1\ndiv", min),
+        format!("push {}\npush -1\nmod", min),
+        format!("push {}\nneg", min),
+        format!("push \"ab\"\npush {}\nmul", i64::MAX),
+    ];
+
+    for program in programs {
+        let commands = parse(&program)?;
+
+        let result = Evaluator::new().evaluate(&commands);
+
+        assert!(matches!(
+            result.map_err(EngineError::root),
+            Err(EngineError::Overflow)
+        ));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn eval_peek() -> Result<(), EngineError> {
+    let commands = parse("push 5\npeek")?;
+
+    let mut evaluator = Evaluator::new();
+    let peeked = evaluator.evaluate(&commands)?;
+    let popped = evaluator.evaluate(&parse("pop")?)?;
+
+    assert_eq!(peeked, Value::Int(5));
+    assert_eq!(popped, Value::Int(5));
+
+    let result = evaluator.evaluate(&parse("pop")?);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::EmptyStack)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn peek_empty() {
+    let evaluator = Evaluator::new();
+
+    assert!(matches!(evaluator.peek(), Err(EngineError::EmptyStack)));
+}
+
+#[test]
+fn eval_stack_contents() -> Result<(), EngineError> {
+    let commands = parse("push 1\npush 2\npush 3")?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate_stack(&commands)?;
+
+    assert_eq!(result, vec![Value::Int(1), Value::Int(2), Value::Int(3)]);
+
+    Ok(())
+}
+
+#[test]
+fn eval_reset() -> Result<(), EngineError> {
+    let mut evaluator = Evaluator::new();
+    evaluator.evaluate(&parse("set x 1\npush 2")?)?;
+
+    evaluator.reset();
+
+    let result = evaluator.evaluate(&parse("get x")?);
+
+    assert!(
+        matches!(result.map_err(EngineError::root), Err(EngineError::MissingVariable(name)) if name == "x")
+    );
+    assert!(evaluator.stack.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn eval_seeded_variable() -> Result<(), EngineError> {
+    let mut evaluator = Evaluator::new();
+    evaluator.set_var("greeting", Value::String("hi".into()));
+
+    let result = evaluator.evaluate(&parse("get greeting")?)?;
+
+    assert_eq!(result, Value::String("hi".into()));
+
+    evaluator.evaluate(&parse("set answer 42")?)?;
+
+    assert_eq!(evaluator.get_var("answer"), Some(&Value::Int(42)));
+    assert_eq!(evaluator.get_var("missing"), None);
+
+    Ok(())
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_value_round_trip() -> Result<(), serde_json::Error> {
+    let values = vec![
+        (Value::Nothing, "null"),
+        (Value::Int(42), "42"),
+        (Value::Float(1.5), "1.5"),
+        (Value::Bool(true), "true"),
+        (Value::String("hello".into()), "\"hello\""),
+        (
+            Value::List(Rc::new(vec![Value::Int(1), Value::String("a".into())])),
+            "[1,\"a\"]",
+        ),
+    ];
+
+    for (value, json) in values {
+        assert_eq!(serde_json::to_string(&value)?, json);
+        assert_eq!(serde_json::from_str::<Value>(json)?, value);
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_type_round_trip() -> Result<(), serde_json::Error> {
+    let types = vec![
+        Type::Int,
+        Type::Float,
+        Type::Bool,
+        Type::String,
+        Type::List,
+        Type::Nothing,
+        Type::Any,
+    ];
+
+    for ty in types {
+        let json = serde_json::to_string(&ty)?;
+
+        assert_eq!(json, format!("\"{}\"", ty));
+        assert_eq!(serde_json::from_str::<Type>(&json)?, ty);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn json_output() {
+    let values = vec![
+        (Value::Nothing, "null"),
+        (Value::Int(42), "42"),
+        (Value::Float(4.0), "4.0"),
+        (Value::Float(f64::NAN), "null"),
+        (Value::Bool(false), "false"),
+        (Value::String("hello".into()), "\"hello\""),
+        (
+            Value::String("say \"hi\"\n\\\u{1}".into()),
+            "\"say \\\"hi\\\"\\n\\\\\\u0001\"",
+        ),
+        (
+            Value::List(Rc::new(vec![Value::Int(1), Value::String("a".into())])),
+            "[1,\"a\"]",
+        ),
+    ];
+
+    for (value, json) in values {
+        assert_eq!(to_json(&value), json);
+    }
+}
+
+#[test]
+fn eval_typeof() -> Result<(), EngineError> {
+    let input = "push \"x\"\ntypeof\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("string".into()));
+
+    Ok(())
+}
+
+#[test]
+fn eval_typeof_names() -> Result<(), EngineError> {
+    let input = "push none\ntypeof\npush 1\ntypeof\npush 1.5\ntypeof\npush true\ntypeof\npush 1\nmakelist 1\ntypeof";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate_stack(&commands)?;
+
+    let names: Vec<Value> = ["nothing", "int", "float", "bool", "list"]
+        .iter()
+        .map(|name| Value::String((*name).into()))
+        .collect();
+
+    assert_eq!(result, names);
+
+    Ok(())
+}
+
+#[test]
+fn eval_toint() -> Result<(), EngineError> {
+    let input = "push \"42\"\ntoint\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(42));
+
+    Ok(())
+}
+
+#[test]
+fn eval_toint_not_numeric() -> Result<(), EngineError> {
+    let input = "push \"forty-two\"\ntoint";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::TypeMismatch {
+            expected: Type::Int,
+            ..
+        })
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_tostr() -> Result<(), EngineError> {
+    let input = "push 42\ntostr\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("42".into()));
+
+    Ok(())
+}
+
+#[test]
+fn display_value() {
+    let values = vec![
+        (Value::Nothing, "", "Nothing"),
+        (Value::Int(42), "42", "Int(42)"),
+        (Value::Float(1.5), "1.5", "Float(1.5)"),
+        (Value::Bool(true), "true", "Bool(true)"),
+        (Value::String("hi".into()), "hi", "String(\"hi\")"),
+        (
+            Value::List(Rc::new(vec![Value::Int(1), Value::String("a".into())])),
+            "[1, a]",
+            "List([Int(1), String(\"a\")])",
+        ),
+    ];
+
+    for (value, display, debug) in values {
+        assert_eq!(value.to_string(), display);
+        assert_eq!(format!("{:?}", value), debug);
+    }
+}
+
+#[test]
+fn eval_jmp() -> Result<(), EngineError> {
+    let input = "push 1\njmp skip\npush 2\nlabel skip:\npush 3\nadd\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(4));
+    assert!(evaluator.stack.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn eval_jmp_unknown_label() -> Result<(), EngineError> {
+    let input = "jmp nowhere";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(
+        matches!(result.map_err(EngineError::root), Err(EngineError::UnknownLabel(name)) if name == "nowhere")
+    );
+
+    Ok(())
+}
+
+#[test]
+fn find_label_positions() -> Result<(), EngineError> {
+    let commands = parse("label start:\npush 1\nlabel end")?;
+
+    let labels = find_labels(&commands);
+
+    assert_eq!(labels.get("start"), Some(&1));
+    assert_eq!(labels.get("end"), Some(&3));
+
+    Ok(())
+}
+
+#[test]
+fn eval_jmp_step_limit() -> Result<(), EngineError> {
+    let input = "label a\njmp a";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    evaluator.max_iterations = 100;
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::StepLimitExceeded)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_jmpif_taken() -> Result<(), EngineError> {
+    let input = "push true\njmpif end\npush 1\nlabel end:\ndepth\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(0));
+
+    Ok(())
+}
+
+#[test]
+fn eval_jmpif_not_taken() -> Result<(), EngineError> {
+    let input = "push false\njmpif end\npush 1\nlabel end:\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(1));
+
+    Ok(())
+}
+
+#[test]
+fn eval_countdown_loop() -> Result<(), EngineError> {
+    let input = "push 3\nlabel top:\npush 1\nsub\ndup\npush 0\ngt\njmpif top\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(0));
+
+    Ok(())
+}
+
+#[test]
+fn eval_jmpif_not_bool() -> Result<(), EngineError> {
+    let input = "push 1\njmpif end\nlabel end:";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_if_true() -> Result<(), EngineError> {
+    let input = "push true\nif\npush \"yes\"\nelse\npush \"no\"\nend\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("yes".into()));
+
+    Ok(())
+}
+
+#[test]
+fn eval_if_false() -> Result<(), EngineError> {
+    let input = "push 1\npush 2\ngt\nif\npush \"yes\"\nelse\npush \"no\"\nend\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("no".into()));
+
+    Ok(())
+}
+
+#[test]
+fn eval_if_without_else() -> Result<(), EngineError> {
+    let input = "set a 1\npush false\nif\nset a 2\nend\nget a";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(1));
+
+    Ok(())
+}
+
+#[test]
+fn eval_if_nested() -> Result<(), EngineError> {
+    let input = "push true; if; push false; if; push 1; else; push 2; end; end; pop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(2));
+
+    Ok(())
+}
+
+#[test]
+fn eval_if_not_bool() -> Result<(), EngineError> {
+    let input = "push 1\nif\nend";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn parse_if_missing_end() {
+    let result = parse("push true\nif\npush 1");
+
+    assert!(matches!(result, Err(EngineError::UnbalancedBlock)));
+}
+
+#[test]
+fn parse_stray_end() {
+    let result = parse("push 1\nend");
+
+    assert!(matches!(
+        result,
+        Err(EngineError::AtLine { span: Span { line: 2, .. }, source }) if matches!(*source, EngineError::UnbalancedBlock)
+    ));
+}
+
+#[test]
+fn parse_stray_else() {
+    let result = parse("else");
+
+    assert!(matches!(
+        result,
+        Err(EngineError::AtLine { span: Span { line: 1, .. }, source }) if matches!(*source, EngineError::UnbalancedBlock)
+    ));
+}
+
+#[test]
+fn typecheck_if() -> Result<(), EngineError> {
+    let input = "push true\nif\npush 1\nelse\npush \"one\"\nend\npush 2\nadd";
+
+    let commands = parse(input)?;
+
+    let mut typechecker = Typechecker::new();
+
+    assert_eq!(typechecker.typecheck(&commands)?, Type::Nothing);
+
+    let input = "push true\nif\npush 1\nelse\npush 2\nend\npop";
+
+    let commands = parse(input)?;
+
+    let mut typechecker = Typechecker::new();
+
+    assert_eq!(typechecker.typecheck(&commands)?, Type::Int);
+
+    let input = "push 1\nif\nend";
+
+    let commands = parse(input)?;
+
+    let mut typechecker = Typechecker::new();
+
+    assert!(matches!(
+        typechecker.typecheck(&commands).map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_while_countdown() -> Result<(), EngineError> {
+    let input = "push 0\npush 5\ndup\npush 0\ngt\nwhile\nswap\npush 1\nadd\nswap\npush 1\nsub\ndup\npush 0\ngt\nend\npop\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(5));
+
+    Ok(())
+}
+
+#[test]
+fn eval_while_never_runs() -> Result<(), EngineError> {
+    let input = "push false\nwhile\npush 1\nend\ndepth\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(0));
+
+    Ok(())
+}
+
+#[test]
+fn eval_while_step_limit() -> Result<(), EngineError> {
+    let input = "push true\nwhile\npush true\nend";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    evaluator.max_iterations = 100;
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::StepLimitExceeded)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn parse_while_missing_end() {
+    let result = parse("push true\nwhile\npush true");
+
+    assert!(matches!(result, Err(EngineError::UnbalancedBlock)));
+}
+
+#[test]
+fn typecheck_while() -> Result<(), EngineError> {
+    let input = "push 5\npush true\nwhile\npush 1\nsub\ndup\npush 0\ngt\nend\npop";
+
+    let commands = parse(input)?;
+
+    let mut typechecker = Typechecker::new();
+
+    assert_eq!(typechecker.typecheck(&commands)?, Type::Int);
+
+    let input = "push 5\npush true\nwhile\npush 1\nend";
+
+    let commands = parse(input)?;
+
+    let mut typechecker = Typechecker::new();
+
+    assert!(matches!(
+        typechecker.typecheck(&commands).map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_def_call() -> Result<(), EngineError> {
+    let input = "def double\ndup\nadd\nend\npush 21\ncall double\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(42));
+
+    Ok(())
+}
+
+#[test]
+fn eval_call_undefined() -> Result<(), EngineError> {
+    let input = "call nothing_here";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(
+        matches!(result.map_err(EngineError::root), Err(EngineError::UnknownCommand(name)) if name == "nothing_here")
+    );
+
+    Ok(())
+}
+
+#[test]
+fn eval_call_recursive() -> Result<(), EngineError> {
+    // Counts down to zero by calling itself
+    let input = "def countdown\npush 1\nsub\ndup\npush 0\ngt\nif\ncall countdown\nend\nend\npush 10\ncall countdown\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(0));
+
+    Ok(())
+}
+
+#[test]
+fn eval_call_recursion_limit() -> Result<(), EngineError> {
+    let input = "def forever\ncall forever\nend\ncall forever";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::RecursionLimit)
+    ));
+
+    evaluator.max_depth = 3;
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::RecursionLimit)
+    ));
+    assert_eq!(evaluator.depth, 0);
+
+    Ok(())
+}
+
+#[test]
+fn eval_trace() -> Result<(), EngineError> {
+    let input = "push 1\npush \"a\"\nswap\nset x 2.0\npop";
+
+    let commands = parse(input)?;
+
+    let buffer = Rc::new(RefCell::new(Vec::new()));
+    let mut evaluator = Evaluator::with_writer(buffer.clone());
+    evaluator.trace = true;
+    evaluator.evaluate(&commands)?;
+
+    let log = String::from_utf8(buffer.borrow().clone()).unwrap();
+
+    assert_eq!(
+        log,
+        "push 1 -> [1]\npush \"a\" -> [1, \"a\"]\nswap -> [\"a\", 1]\nset x 2.0 -> [\"a\", 1]\npop -> [\"a\"]\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn eval_trace_off() -> Result<(), EngineError> {
+    let input = "push 1\npush 2\nadd";
+
+    let commands = parse(input)?;
+
+    let buffer = Rc::new(RefCell::new(Vec::new()));
+    let mut evaluator = Evaluator::with_writer(buffer.clone());
+    evaluator.evaluate(&commands)?;
+
+    assert!(buffer.borrow().is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn eval_max_stack() -> Result<(), EngineError> {
+    let input = "push 1\npush 2\npush 3";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::with_limits(2);
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::StackOverflow)
+    ));
+    assert_eq!(evaluator.stack.len(), 2);
+
+    let input = "push 1\npush 2\nadd\npush 3";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::with_limits(2);
+    evaluator.evaluate(&commands)?;
+
+    assert_eq!(evaluator.stack, vec![Value::Int(3), Value::Int(3)]);
+
+    Ok(())
+}
+
+#[test]
+fn eval_unlimited_stack() -> Result<(), EngineError> {
+    let input = "push 0\npush true\nwhile\ndup\npush 1\nadd\ndup\npush 10000\nlt\nend\ndepth\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+
+    assert_eq!(evaluator.max_stack, None);
+
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(10001));
+
+    Ok(())
+}
+
+#[test]
+fn eval_pick_top() -> Result<(), EngineError> {
+    let input = "push 1\npush 2\npick 0";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate_stack(&commands)?;
+
+    assert_eq!(result, vec![Value::Int(1), Value::Int(2), Value::Int(2)]);
+
+    Ok(())
+}
+
+#[test]
+fn eval_pick_below() -> Result<(), EngineError> {
+    let input = "push 1\npush 2\npick 1";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate_stack(&commands)?;
+
+    assert_eq!(result, vec![Value::Int(1), Value::Int(2), Value::Int(1)]);
+
+    Ok(())
+}
+
+#[test]
+fn eval_pick_out_of_range() -> Result<(), EngineError> {
+    let input = "push 1\npush 2\npick 2";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::EmptyStack)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_pick_huge_depth() -> Result<(), EngineError> {
+    let input = "push 1\npick 18446744073709551615";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::EmptyStack)
+    ));
+
+    let mut typechecker = Typechecker::new();
+    let result = typechecker.typecheck(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::EmptyStack)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_bitwise() -> Result<(), EngineError> {
+    for (input, expected) in [
+        ("push 12\npush 10\nband", 8),
+        ("push 12\npush 10\nbor", 14),
+        ("push 12\npush 10\nbxor", 6),
+        ("push 1\npush 4\nshl", 16),
+        ("push -16\npush 2\nshr", -4),
+        ("push 0\nbnot", -1),
+    ] {
+        let commands = parse(input)?;
+
+        let mut evaluator = Evaluator::new();
+        let result = evaluator.evaluate_stack(&commands)?;
+
+        assert_eq!(result, vec![Value::Int(expected)], "{}", input);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn eval_bitwise_not_int() -> Result<(), EngineError> {
+    let input = "push 1.5\npush 1\nband";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    let input = "push true\nbnot";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_invalid_shift() -> Result<(), EngineError> {
+    for input in ["push 1\npush 64\nshl", "push 1\npush -1\nshr"] {
+        let commands = parse(input)?;
+
+        let mut evaluator = Evaluator::new();
+        let result = evaluator.evaluate(&commands);
+
+        assert!(
+            matches!(
+                result.map_err(EngineError::root),
+                Err(EngineError::InvalidShift)
+            ),
+            "{}",
+            input
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn eval_sum() -> Result<(), EngineError> {
+    let input = "push 1\npush 2\npush 3\npush 4\nsum\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(10));
+    assert!(evaluator.stack.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn eval_sum_empty() -> Result<(), EngineError> {
+    let input = "sum\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(0));
+
+    Ok(())
+}
+
+#[test]
+fn eval_sum_mixed() -> Result<(), EngineError> {
+    let input = "push 1\npush \"two\"\nsum";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn optimize_constants() -> Result<(), EngineError> {
+    let input = "push 1; push 2; add; push 3; mul; push 4; sub; neg; pop";
+
+    let commands = optimize(parse(input)?);
+
+    assert_eq!(commands.len(), 2);
+    assert!(matches!(commands[0], Command::Push(Value::Int(-5))));
+
+    let input = "push true; if; push 6; push 7; mul; pop; end";
+
+    let commands = optimize(parse(input)?);
+
+    match &commands[1] {
+        Command::If(then, _) => assert_eq!(then.len(), 2),
+        _ => panic!("expected an if"),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn optimize_same_results() -> Result<(), EngineError> {
+    for input in [
+        "push 10; push 3; sub; push 2; mod; pop",
+        "push 1; push 4; shl; push 3; bor; push 2; lt; pop",
+        "push 7; push 0; div; pop",
+        "push 9223372036854775807; push 1; add; pop",
+        "push 1; push 64; shl; pop",
+        "push \"a\"; push 1; add; pop",
+        "push 2; push 3; label top; add; pop",
+    ] {
+        let mut evaluator = Evaluator::new();
+        let expected = evaluator.evaluate(&parse(input)?);
+
+        let mut evaluator = Evaluator::new();
+        let result = evaluator.evaluate(&optimize(parse(input)?));
+
+        match (expected, result) {
+            (Ok(expected), Ok(result)) => assert_eq!(expected, result, "{}", input),
+            (Err(expected), Err(result)) => {
+                assert_eq!(expected.to_string(), result.to_string(), "{}", input)
+            }
+            (expected, result) => panic!("{}: {:?} vs {:?}", input, expected, result),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn eval_dump_load() -> Result<(), EngineError> {
+    let path = std::env::temp_dir().join(format!("onehour-dump-{}.json", std::process::id()));
+    let path = literal(&Value::String(path.to_string_lossy().into()));
+
+    let input = format!("set a 42\nset b \"hi\"\npush 1.0\ndump {}", path);
+
+    let commands = parse(&input)?;
+
+    let mut evaluator = Evaluator::new();
+    evaluator.evaluate(&commands)?;
+
+    evaluator.reset();
+
+    assert_eq!(evaluator.get_var("a"), None);
+
+    let input = format!("load {}\nget a", path);
+
+    let commands = parse(&input)?;
+
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(42));
+    assert_eq!(evaluator.get_var("b"), Some(&Value::String("hi".into())));
+    assert_eq!(evaluator.stack, vec![Value::Float(1.0)]);
+
+    let _ = std::fs::remove_file(parse_string(&path)?.to_string());
+
+    Ok(())
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn parse_dump_unquoted_path() {
+    let result = parse("dump state.json");
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn eval_load_missing_file() -> Result<(), EngineError> {
+    let input = "load \"/nonexistent/onehour/state.json\"";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::Io(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_sort_ints() -> Result<(), EngineError> {
+    let input = "push 3\npush -1\npush 2\nmakelist 3\nsort\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(
+        result,
+        Value::List(Rc::new(vec![Value::Int(-1), Value::Int(2), Value::Int(3)]))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn eval_sort_strings() -> Result<(), EngineError> {
+    let input = "push \"pear\"\npush \"apple\"\npush \"fig\"\nmakelist 3\nsort\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(
+        result,
+        Value::List(Rc::new(vec![
+            Value::String("apple".into()),
+            Value::String("fig".into()),
+            Value::String("pear".into()),
+        ]))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn eval_sort_mixed() -> Result<(), EngineError> {
+    let input = "push 1\npush \"a\"\nmakelist 2\nsort";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_reverse_list() -> Result<(), EngineError> {
+    let input = "push 1\npush 2\npush 3\nmakelist 3\nreverse\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(
+        result,
+        Value::List(Rc::new(vec![Value::Int(3), Value::Int(2), Value::Int(1)]))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn eval_reverse_string() -> Result<(), EngineError> {
+    let input = "push \"héllo→\"\nreverse\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("→olléh".into()));
+
+    Ok(())
+}
+
+#[test]
+fn eval_reverse_wrong_type() -> Result<(), EngineError> {
+    let input = "push 12\nreverse";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_range() -> Result<(), EngineError> {
+    let input = "push -1\npush 3\nrange\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(
+        result,
+        Value::List(Rc::new(vec![
+            Value::Int(-1),
+            Value::Int(0),
+            Value::Int(1),
+            Value::Int(2),
+        ]))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn eval_range_empty() -> Result<(), EngineError> {
+    let input = "push 5\npush 2\nrange\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::List(Rc::new(vec![])));
+
+    Ok(())
+}
+
+#[test]
+fn eval_range_too_long() -> Result<(), EngineError> {
+    for input in [
+        "push 0\npush 9223372036854775807\nrange\npop",
+        "push -9223372036854775808\npush 9223372036854775807\nrange\npop",
+    ] {
+        let commands = parse(input)?;
+
+        let mut evaluator = Evaluator::new();
+        let result = evaluator.evaluate(&commands);
+
+        assert!(matches!(
+            result.map_err(EngineError::root),
+            Err(EngineError::Overflow)
+        ));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn eval_range_wrong_type() -> Result<(), EngineError> {
+    let input = "push 0\npush \"5\"\nrange";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_contains_string() -> Result<(), EngineError> {
+    let input = "push \"hello world\"\npush \"o w\"\ncontains\npush \"hello world\"\npush \"low\"\ncontains";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate_stack(&commands)?;
+
+    assert_eq!(result, vec![Value::Bool(true), Value::Bool(false)]);
+
+    Ok(())
+}
+
+#[test]
+fn eval_contains_list() -> Result<(), EngineError> {
+    let input =
+        "push 1\npush \"two\"\nmakelist 2\ndup\npush \"two\"\ncontains\nswap\npush 2\ncontains";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate_stack(&commands)?;
+
+    assert_eq!(result, vec![Value::Bool(true), Value::Bool(false)]);
+
+    Ok(())
+}
+
+#[test]
+fn eval_contains_wrong_type() -> Result<(), EngineError> {
+    let input = "push \"abc\"\npush 1\ncontains";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_scope_hides_inner_vars() -> Result<(), EngineError> {
+    let input = "scope\nset inner 1\nendscope\nget inner";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(
+        matches!(result.map_err(EngineError::root), Err(EngineError::MissingVariable(name)) if name == "inner")
+    );
+
+    Ok(())
+}
+
+#[test]
+fn eval_scope_sees_outer_vars() -> Result<(), EngineError> {
+    let input = "set outer 1\nscope\nset outer 2\npushvar outer\nendscope\npushvar outer";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate_stack(&commands)?;
+
+    assert_eq!(result, vec![Value::Int(2), Value::Int(1)]);
+
+    let input = "set outer 1\nscope\nget outer";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(1));
+
+    Ok(())
+}
+
+#[test]
+fn eval_endscope_without_scope() -> Result<(), EngineError> {
+    let input = "endscope";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::UnbalancedBlock)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn typecheck_scope() -> Result<(), EngineError> {
+    let input = "set x 1\nscope\nset x \"a\"\nendscope\npushvar x\npush 1\nadd\npop";
+
+    let commands = parse(input)?;
+
+    let mut typechecker = Typechecker::new();
+
+    assert_eq!(typechecker.typecheck(&commands)?, Type::Int);
+
+    Ok(())
+}
+
+#[test]
+fn eval_const() -> Result<(), EngineError> {
+    let input = "const answer 42\npushvar answer\nget answer";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(42));
+    assert_eq!(evaluator.stack, vec![Value::Int(42)]);
+
+    Ok(())
+}
+
+#[test]
+fn eval_const_reassignment() -> Result<(), EngineError> {
+    for input in ["const x 1\nset x 2", "const x 1\nconst x 2"] {
+        let commands = parse(input)?;
+
+        let mut evaluator = Evaluator::new();
+        let result = evaluator.evaluate(&commands);
+
+        assert!(
+            matches!(result.map_err(EngineError::root), Err(EngineError::ConstReassignment(ref name)) if name == "x"),
+            "{}",
+            input
+        );
+        assert_eq!(evaluator.get_var("x"), Some(&Value::Int(1)));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn eval_const_out_of_scope() -> Result<(), EngineError> {
+    let input = "scope\nconst x 1\nendscope\nset x 2\nget x";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(2));
+
+    Ok(())
+}
+
+#[test]
+fn eval_const_deleted() -> Result<(), EngineError> {
+    let input = "const y 1\ndel y\nset y 3\ninc y\nget y";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(4));
+
+    Ok(())
+}
+
+#[test]
+fn eval_list_vars() -> Result<(), EngineError> {
+    let input = "set zebra 1\nset apple 2\nvars\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(
+        result,
+        Value::List(Rc::new(vec![
+            Value::String("apple".into()),
+            Value::String("zebra".into()),
+        ]))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn eval_has_var() -> Result<(), EngineError> {
+    let input = "set defined 1\nhas defined\nhas undefined";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate_stack(&commands)?;
+
+    assert_eq!(result, vec![Value::Bool(true), Value::Bool(false)]);
+
+    Ok(())
+}
+
+#[test]
+fn bytecode_round_trip() -> Result<(), EngineError> {
+    let input = r#"set greeting "hi\tthere"
+const limit 1.5
+push none
+push true
+push -42
+makelist 2
+pick 0
+def twice
+dup
+add
+end
+push 1
+if
+call twice
+else
+push "nope"
+end
+label again
+push false
+jmpif again
+while
+push false
+end
+"#;
+
+    let commands = parse(input)?;
+
+    let bytes = serialize_commands(&commands);
+    let decoded = deserialize_commands(&bytes)?;
+
+    assert_eq!(decoded, commands);
+
+    let mut evaluator = Evaluator::new();
+    let expected = evaluator.evaluate_stack(&commands);
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate_stack(&decoded);
+
+    assert_eq!(format!("{:?}", result), format!("{:?}", expected));
+
+    Ok(())
+}
+
+#[test]
+fn bytecode_truncated() -> Result<(), EngineError> {
+    let commands = parse("set x \"hello\"\npush 12\nadd")?;
+
+    let bytes = serialize_commands(&commands);
+
+    for len in 0..bytes.len() {
+        let result = deserialize_commands(&bytes[..len]);
+
+        assert!(matches!(result, Err(EngineError::BadBytecode)), "{}", len);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn bytecode_bad_tag() {
+    let result = deserialize_commands(&[1, 0, 0, 0, 255]);
+
+    assert!(matches!(result, Err(EngineError::BadBytecode)));
+
+    // Trailing bytes after the program
+    let mut bytes = serialize_commands(&[Command::Pop]);
+    bytes.push(0);
+    let result = deserialize_commands(&bytes);
+
+    assert!(matches!(result, Err(EngineError::BadBytecode)));
+}
+
+#[test]
+fn bytecode_nested_too_deep() -> Result<(), EngineError> {
+    // A while inside a while inside a while...
+    let nested = |depth: usize| {
+        let mut bytes = vec![1, 0, 0, 0];
+        for _ in 0..depth {
+            bytes.extend_from_slice(&[44, 1, 0, 0, 0]);
+        }
+        // The innermost body is empty
+        bytes[4 + (depth - 1) * 5 + 1] = 0;
+        bytes
+    };
+
+    let deepest = nested(MAX_BYTECODE_DEPTH - 1);
+    let commands = deserialize_commands(&deepest)?;
+
+    assert_eq!(serialize_commands(&commands), deepest);
+
+    let result = deserialize_commands(&nested(200_000));
+
+    assert!(matches!(result, Err(EngineError::BadBytecode)));
+
+    Ok(())
+}
+
+#[test]
+fn disassemble_round_trip() -> Result<(), EngineError> {
+    let input = r#"set greeting "say \"hi\"\n"
+push 1.0
+push 10000000000000000.0; push 0.0000001; push -2.5
+push none
+def twice
+dup; add
+end
+push 2; call twice
+push 4; eq
+if
+    push "four"
+else
+    push "not four"
+end
+pop
+while # never runs
+push false
+end
+label done:
+"#;
+
+    let commands = parse(input)?;
+
+    let source = disassemble(&commands);
+
+    assert_eq!(parse(&source)?, commands);
+
+    assert!(source.starts_with(
+        "set greeting \"say \\\"hi\\\"\\n\"\npush 1.0\npush 10000000000000000.0\npush 0.0000001\npush -2.5\npush none\ndef twice\n    dup\n"
+    ));
+    assert!(source.contains("if\n    push \"four\"\nelse\n    push \"not four\"\nend\n"));
+
+    Ok(())
+}
+
+#[test]
+fn parse_float_special_values() -> Result<(), EngineError> {
+    for value in [f64::INFINITY, f64::NEG_INFINITY, f64::NAN] {
+        let source = literal(&Value::Float(value));
+
+        let result = parse_value(&source)?;
+
+        match result {
+            Value::Float(x) if value.is_nan() => assert!(x.is_nan(), "{}", source),
+            Value::Float(x) => assert_eq!(x, value, "{}", source),
+            _ => panic!("{} didn't parse as a float", source),
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn eval_step_count() -> Result<(), EngineError> {
+    let input = "push 1\npush 2\nadd\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    evaluator.evaluate(&commands)?;
+
+    assert_eq!(evaluator.step_count(), 4);
+
+    evaluator.reset();
+
+    assert_eq!(evaluator.step_count(), 0);
+
+    // Each command in a loop body counts every time it runs
+    let input = "push 3\npush true\nwhile\npush 1\nsub\ndup\npush 0\ngt\nend";
+
+    let commands = parse(input)?;
+
+    evaluator.evaluate(&commands)?;
+
+    assert_eq!(evaluator.step_count(), 3 + 3 * 5);
+
+    Ok(())
+}
+
+#[test]
+fn eval_swapvar() -> Result<(), EngineError> {
+    let input = "set a 1\nset b \"two\"\nswapvar a b\npushvar a\npushvar b";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate_stack(&commands)?;
+
+    assert_eq!(result, vec![Value::String("two".into()), Value::Int(1)]);
+
+    Ok(())
+}
+
+#[test]
+fn eval_swapvar_missing() -> Result<(), EngineError> {
+    for (input, missing) in [("set a 1\nswapvar a b", "b"), ("set b 1\nswapvar a b", "a")] {
+        let commands = parse(input)?;
+
+        let mut evaluator = Evaluator::new();
+        let result = evaluator.evaluate(&commands);
+
+        assert!(
+            matches!(result.map_err(EngineError::root), Err(EngineError::MissingVariable(ref name)) if name == missing),
+            "{}",
+            input
+        );
+        // Nothing was changed
+        assert_eq!(evaluator.get_var(missing), None);
+        assert_eq!(evaluator.all_vars().len(), 1);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn eval_inc_dec() -> Result<(), EngineError> {
+    let input = "set counter 10\ninc counter\ninc counter\nget counter";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(12));
+
+    let input = "dec counter\ndec counter\ndec counter\nget counter";
+
+    let commands = parse(input)?;
+
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(9));
+
+    Ok(())
+}
+
+#[test]
+fn eval_inc_not_int() -> Result<(), EngineError> {
+    let input = "set name \"bob\"\ninc name";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    let input = "dec missing";
+
+    let commands = parse(input)?;
+
+    let result = evaluator.evaluate(&commands);
+
+    assert!(
+        matches!(result.map_err(EngineError::root), Err(EngineError::MissingVariable(name)) if name == "missing")
+    );
+
+    Ok(())
+}
+
+#[test]
+fn eval_min_max_ints() -> Result<(), EngineError> {
+    let input = "push 3\npush -7\nmin\npush 3\npush -7\nmax";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate_stack(&commands)?;
+
+    assert_eq!(result, vec![Value::Int(-7), Value::Int(3)]);
+
+    Ok(())
+}
+
+#[test]
+fn eval_min_max_strings() -> Result<(), EngineError> {
+    let input = "push \"pear\"\npush \"apple\"\nmin\npush \"pear\"\npush \"apple\"\nmax";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate_stack(&commands)?;
+
+    assert_eq!(
+        result,
+        vec![Value::String("apple".into()), Value::String("pear".into())]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn eval_min_mixed() -> Result<(), EngineError> {
+    let input = "push 1\npush \"a\"\nmin";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_pow() -> Result<(), EngineError> {
+    let input = "push 3\npush 4\npow\npush -2\npush 3\npow\npush 5\npush 0\npow";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate_stack(&commands)?;
+
+    assert_eq!(result, vec![Value::Int(81), Value::Int(-8), Value::Int(1)]);
+
+    Ok(())
+}
+
+#[test]
+fn eval_pow_overflow() -> Result<(), EngineError> {
+    for input in ["push 2\npush 63\npow", "push 10\npush 10000000000\npow"] {
+        let commands = parse(input)?;
+
+        let mut evaluator = Evaluator::new();
+        let result = evaluator.evaluate(&commands);
+
+        assert!(
+            matches!(
+                result.map_err(EngineError::root),
+                Err(EngineError::Overflow)
+            ),
+            "{}",
+            input
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn eval_pow_negative_exponent() -> Result<(), EngineError> {
+    let input = "push 2\npush -1\npow";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_abs() -> Result<(), EngineError> {
+    let input = "push 5\nabs\npush -5\nabs";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate_stack(&commands)?;
+
+    assert_eq!(result, vec![Value::Int(5), Value::Int(5)]);
+
+    Ok(())
+}
+
+#[test]
+fn eval_abs_min_int() -> Result<(), EngineError> {
+    let input = "push -9223372036854775808\nabs";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::Overflow)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_abs_not_int() -> Result<(), EngineError> {
+    let input = "push \"-5\"\nabs";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_trim() -> Result<(), EngineError> {
+    let input = "push \"  hi  \"\ntrim\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("hi".into()));
+
+    Ok(())
+}
+
+#[test]
+fn eval_trim_not_string() -> Result<(), EngineError> {
+    let input = "push 1\ntrim";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_replace() -> Result<(), EngineError> {
+    let input = "push \"one fish two fish\"\npush \"fish\"\npush \"cat\"\nreplace\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("one cat two cat".into()));
+
+    Ok(())
+}
+
+#[test]
+fn eval_replace_no_match() -> Result<(), EngineError> {
+    let input = "push \"hello\"\npush \"xyz\"\npush \"abc\"\nreplace\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("hello".into()));
+
+    Ok(())
+}
+
+#[test]
+fn eval_replace_empty_pattern() -> Result<(), EngineError> {
+    let input = "push \"hello\"\npush \"\"\npush \"-\"\nreplace\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("hello".into()));
+
+    let input = "push \"hello\"\npush 1\npush \"-\"\nreplace";
+
+    let commands = parse(input)?;
+
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_join_strings() -> Result<(), EngineError> {
+    let input = "push \"a\"\npush \"b\"\npush \"c\"\nmakelist 3\npush \",\"\njoin\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("a,b,c".into()));
+
+    Ok(())
+}
+
+#[test]
+fn eval_join_mixed() -> Result<(), EngineError> {
+    let input = "push 1\npush \"two\"\npush none\npush 4\nmakelist 4\npush \" \"\njoin\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("1 two  4".into()));
+
+    let input = "push \"abc\"\npush \",\"\njoin";
+
+    let commands = parse(input)?;
+
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_vars_sorted() -> Result<(), EngineError> {
+    let input = "set m 1\nset b 2\nset z 3\nset a 4\nscope\nset c 5\ndel b\nset b 6";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    evaluator.evaluate(&commands)?;
+
+    let vars = evaluator.all_vars();
+    let names: Vec<_> = vars.keys().map(|name| name.as_str()).collect();
+
+    assert_eq!(names, vec!["a", "b", "c", "m", "z"]);
+    assert_eq!(vars["b"], Value::Int(6));
+    assert_eq!(evaluator.get_var("z"), Some(&Value::Int(3)));
+
+    let commands = parse("pushvar m\ndel m\nhas m")?;
+
+    let result = evaluator.evaluate_stack(&commands)?;
+
+    assert_eq!(result, vec![Value::Int(1), Value::Bool(false)]);
+
+    Ok(())
+}
+
+#[test]
+fn eval_nip() -> Result<(), EngineError> {
+    let input = "push 1\npush 2\npush 3\nnip";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate_stack(&commands)?;
+
+    assert_eq!(result, vec![Value::Int(1), Value::Int(3)]);
+
+    Ok(())
+}
+
+#[test]
+fn eval_tuck() -> Result<(), EngineError> {
+    let input = "push 1\npush 2\npush 3\ntuck";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate_stack(&commands)?;
+
+    assert_eq!(
+        result,
+        vec![Value::Int(1), Value::Int(3), Value::Int(2), Value::Int(3)]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn eval_nip_tuck_empty() -> Result<(), EngineError> {
+    for input in ["push 1\nnip", "push 1\ntuck"] {
+        let commands = parse(input)?;
+
+        let mut evaluator = Evaluator::new();
+        let result = evaluator.evaluate(&commands);
+
+        assert!(
+            matches!(
+                result.map_err(EngineError::root),
+                Err(EngineError::EmptyStack)
+            ),
+            "{}",
+            input
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn eval_two_dup() -> Result<(), EngineError> {
+    let input = "push 1\npush \"b\"\n2dup";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate_stack(&commands)?;
+
+    assert_eq!(
+        result,
+        vec![
+            Value::Int(1),
+            Value::String("b".into()),
+            Value::Int(1),
+            Value::String("b".into()),
+        ]
+    );
+
+    let commands = parse("clear\npush 1\n2dup")?;
+
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::EmptyStack)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_caesar() -> Result<(), EngineError> {
+    let input = "push \"Hello, World!\"\ncaesar 3\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("Khoor, Zruog!".into()));
+
+    Ok(())
+}
+
+#[test]
+fn eval_caesar_negative() -> Result<(), EngineError> {
+    let input = "push \"abc XYZ\"\ncaesar -29\ncaesar 9223372036854775807\ncaesar 19\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("xyz UVW".into()));
+
+    Ok(())
+}
+
+#[test]
+fn eval_caesar_keeps_non_letters() -> Result<(), EngineError> {
+    let input = "push \"12 + é_ü?\"\ncaesar 13\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("12 + é_ü?".into()));
+
+    let commands = parse("push 1\ncaesar 1")?;
+
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_format() -> Result<(), EngineError> {
+    let input = "push \"Ada\"\npush 36\npush \"{0} is {1}, {0}!\"\nformat 2\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("Ada is 36, Ada!".into()));
+
+    Ok(())
+}
+
+#[test]
+fn eval_format_missing_argument() -> Result<(), EngineError> {
+    let input = "push 1\npush \"{0} and {1}\"\nformat 1\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::BadFormat)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_format_no_placeholders() -> Result<(), EngineError> {
+    let input = "push \"plain {text}\"\nformat 0\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("plain {text}".into()));
+
+    Ok(())
+}
+
+#[test]
+fn type_of_each_value() {
+    let cases = vec![
+        (Value::Nothing, Type::Nothing),
+        (Value::Int(1), Type::Int),
+        (Value::Float(1.5), Type::Float),
+        (Value::Bool(true), Type::Bool),
+        (Value::String("a".into()), Type::String),
+        (Value::List(Rc::new(vec![Value::Int(1)])), Type::List),
+    ];
+
+    for (value, ty) in cases {
+        assert_eq!(Type::of(&value), ty);
+    }
+}
+
+#[test]
+fn eval_assert() -> Result<(), EngineError> {
+    let input = "push 2\npush 2\neq\nassert\npush 1\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(1));
+
+    Ok(())
+}
+
+#[test]
+fn eval_assert_fails() -> Result<(), EngineError> {
+    let input = "push 2\npush 3\neq\nassert\npush 1\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::AssertionFailed { .. })
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn eval_assert_eq() -> Result<(), EngineError> {
+    let input = "push 4\npush 2\npush 2\nadd\nassert_eq\ndepth\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(0));
 
-#[derive(Debug)]
-enum EngineError {
-    MismatchNumParams,
-    MimatchType,
-    UnknownCommand(String),
-    MissingVariable(String),
-    EmptyStack,
+    Ok(())
 }
 
-struct Evaluator {
-    vars: HashMap<String, Value>,
-    stack: Vec<Value>,
+#[test]
+fn eval_assert_eq_unequal() -> Result<(), EngineError> {
+    let input = "push 1\npush 2\nassert_eq";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::AssertionFailed {
+            left: Value::Int(1),
+            right: Value::Int(2),
+        })
+    ));
+
+    Ok(())
 }
 
-impl Evaluator {
-    fn new() -> Evaluator {
-        Self {
-            vars: HashMap::new(),
-            stack: vec![],
-        }
-    }
+#[test]
+fn eval_assert_eq_mismatched_types() -> Result<(), EngineError> {
+    let input = "push \"1\"\npush 1\nassert_eq";
 
-    fn pop(&mut self) -> Result<Value, EngineError> {
-        let result = self.stack.pop();
-        match result {
-            Some(v) => Ok(v),
-            None => return Err(EngineError::EmptyStack),
-        }
-    }
+    let commands = parse(input)?;
 
-    fn add(&self, lhs: Value, rhs: Value) -> Result<Value, EngineError> {
-        match (lhs, rhs) {
-            (Value::Int(i1), Value::Int(i2)) => Ok(Value::Int(i1 + i2)),
-            (Value::String(s1), Value::String(s2)) => Ok(Value::String(s1 + &s2)),
-            _ => Err(EngineError::MimatchType),
-        }
-    }
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
 
-    fn evaluate(&mut self, commands: &[Command]) -> Result<Value, EngineError> {
-        let mut output = Ok(Value::Nothing);
-        for command in commands {
-            match command {
-                Command::SetVar(name, value) => {
-                    self.vars.insert(name.into(), value.clone());
-                }
-                Command::GetVar(name) => match self.vars.get(name) {
-                    Some(value) => output = Ok(value.clone()),
-                    None => return Err(EngineError::MissingVariable(name.into())),
-                },
-                Command::PushVar(name) => match self.vars.get(name) {
-                    Some(value) => self.stack.push(value.clone()),
-                    None => return Err(EngineError::MissingVariable(name.into())),
-                },
-                Command::Push(v) => self.stack.push(v.clone()),
-                Command::Pop => {
-                    output = self.pop();
-                }
-                Command::Add => {
-                    let lhs = self.pop()?;
-                    let rhs = self.pop()?;
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::AssertionFailed { .. })
+    ));
 
-                    let result = self.add(lhs, rhs)?;
-                    self.stack.push(result)
-                }
-            }
-        }
-        output
-    }
+    Ok(())
 }
 
-fn parse_var_name(var_name: &str) -> Result<String, EngineError> {
-    Ok(var_name.into())
-}
+#[test]
+fn eval_concat() -> Result<(), EngineError> {
+    let input = "push \"foo\"\npush \"bar\"\nconcat\npop";
 
-fn parse_string(val: &str) -> Result<Value, EngineError> {
-    if val.starts_with('\"') && val.ends_with('\"') && val.len() > 1 {
-        let inner = val[1..(val.len() - 1)].to_string();
+    let commands = parse(input)?;
 
-        Ok(Value::String(inner))
-    } else {
-        Err(EngineError::MimatchType)
-    }
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("foobar".into()));
+
+    Ok(())
 }
 
-fn parse_int(val: &str) -> Result<Value, EngineError> {
-    let result = val.parse::<i64>();
+#[test]
+fn eval_concat_int_string() -> Result<(), EngineError> {
+    let input = "push 1\npush \"st\"\nconcat\npush 1\npush 2\nconcat\nconcat\npop";
 
-    match result {
-        Ok(x) => Ok(Value::Int(x)),
-        _ => Err(EngineError::MimatchType),
-    }
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("1st12".into()));
+
+    Ok(())
 }
 
-fn parse_value(val: &str) -> Result<Value, EngineError> {
-    if val.starts_with("\"") && val.ends_with("\"") && val.len() > 1 {
-        // Parse the string
-        parse_string(val)
-    } else {
-        // Parse the number
-        parse_int(val)
-    }
+#[test]
+fn eval_concat_nothing() -> Result<(), EngineError> {
+    let input = "push none\npush \"abc\"\nconcat\npop";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("abc".into()));
+
+    Ok(())
 }
 
-fn parse_set(input: &[&str]) -> Result<Command, EngineError> {
-    if input.len() != 3 {
-        return Err(EngineError::MismatchNumParams);
-    }
+#[test]
+fn eval_repeat() -> Result<(), EngineError> {
+    let input = "repeat 3\npush 1\nend";
 
-    let var_name = parse_var_name(input[1])?;
-    let value = parse_value(input[2])?;
+    let commands = parse(input)?;
 
-    Ok(Command::SetVar(var_name, value))
+    let mut evaluator = Evaluator::new();
+    evaluator.evaluate(&commands)?;
+
+    assert_eq!(
+        evaluator.stack,
+        vec![Value::Int(1), Value::Int(1), Value::Int(1)]
+    );
+
+    Ok(())
 }
 
-fn parse_get(input: &[&str]) -> Result<Command, EngineError> {
-    if input.len() != 2 {
-        return Err(EngineError::MismatchNumParams);
-    }
+#[test]
+fn eval_repeat_zero() -> Result<(), EngineError> {
+    let input = "repeat 0\npush 1\nend\ndepth\npop";
 
-    let var_name = parse_var_name(input[1])?;
+    let commands = parse(input)?;
 
-    Ok(Command::GetVar(var_name))
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(0));
+
+    Ok(())
 }
 
-fn parse_pushvar(input: &[&str]) -> Result<Command, EngineError> {
-    if input.len() != 2 {
-        return Err(EngineError::MismatchNumParams);
+#[test]
+fn parse_repeat_bad_count() {
+    for input in ["repeat -1\npush 1\nend", "repeat x\npush 1\nend"] {
+        let result = parse(input);
+
+        assert!(matches!(
+            result,
+            Err(EngineError::AtLine { source, .. })
+                if matches!(*source, EngineError::TypeMismatch { .. })
+        ));
     }
+}
 
-    let var_name = parse_var_name(input[1])?;
+#[test]
+fn eval_repeat_step_limit() -> Result<(), EngineError> {
+    let input = "repeat 5\npush 1\nend";
 
-    Ok(Command::PushVar(var_name))
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    evaluator.max_iterations = 3;
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::StepLimitExceeded)
+    ));
+
+    Ok(())
 }
 
-fn parse_push(input: &[&str]) -> Result<Command, EngineError> {
-    if input.len() != 2 {
-        return Err(EngineError::MismatchNumParams);
-    }
+#[test]
+fn typecheck_repeat() -> Result<(), EngineError> {
+    let input = "set x 0\nrepeat 3\ninc x\nend\npushvar x\npop";
 
-    let val = parse_value(input[1])?;
+    let commands = parse(input)?;
 
-    Ok(Command::Push(val))
+    let mut typechecker = Typechecker::new();
+    let result = typechecker.typecheck(&commands)?;
+
+    assert_eq!(result, Type::Int);
+
+    Ok(())
 }
 
-fn parse(input: &str) -> Result<Vec<Command>, EngineError> {
-    // set a 100
-    // get a
+#[test]
+fn eval_error_at_command() -> Result<(), EngineError> {
+    let input = "push 1\npush \"a\"\nadd\npop";
 
-    let mut output = vec![];
+    let commands = parse(input)?;
 
-    for line in input.lines() {
-        let command: Vec<_> = line.split_ascii_whitespace().collect();
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
 
-        match command.get(0) {
-            Some(x) if *x == "set" => {
-                output.push(parse_set(&command)?);
-            }
-            Some(x) if *x == "get" => {
-                output.push(parse_get(&command)?);
-            }
-            Some(x) if *x == "push" => {
-                output.push(parse_push(&command)?);
-            }
-            Some(x) if *x == "pushvar" => {
-                output.push(parse_pushvar(&command)?);
-            }
-            Some(x) if *x == "pop" => {
-                output.push(Command::Pop);
-            }
-            Some(x) if *x == "add" => {
-                output.push(Command::Add);
-            }
-            Some(name) => return Err(EngineError::UnknownCommand(name.to_string())),
-            None => {}
+    match result {
+        Err(EngineError::AtCommand {
+            index,
+            command,
+            source,
+        }) => {
+            assert_eq!(index, 2);
+            assert_eq!(command, "add");
+            assert!(matches!(*source, EngineError::TypeMismatch { .. }));
         }
+        _ => panic!("expected an error at the add"),
     }
 
-    Ok(output)
+    Ok(())
 }
 
-struct Typechecker {
-    stack: Vec<Type>,
+#[test]
+fn eval_error_at_command_nested() -> Result<(), EngineError> {
+    let input = "def broken\npush 1\npush 0\ndiv\nend\npush true\nif\ncall broken\nend";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+    let err = result.unwrap_err();
+
+    assert_eq!(
+        err.to_string(),
+        "command 2 (if): command 0 (call broken): command 2 (div): division by zero"
+    );
+    assert!(matches!(err.root(), EngineError::DivideByZero));
+
+    Ok(())
 }
 
-impl Typechecker {
-    fn typecheck_command(&mut self, commands: &Command) -> Result<Type, EngineError> {
-        Ok(Type::Nothing)
-    }
+#[test]
+fn eval_clone() -> Result<(), EngineError> {
+    let mut evaluator = Evaluator::new();
+    evaluator.evaluate(&parse("set x 1\npush 1\npush 2")?)?;
 
-    fn typecheck(&mut self, commands: &[Command]) -> Result<Type, EngineError> {
-        for command in commands {
-            self.typecheck_command(command)?;
-        }
-        Ok(Type::Nothing)
-    }
+    let mut snapshot = evaluator.clone();
+    snapshot.evaluate(&parse("add\nset x 2\nset y 3")?)?;
+
+    assert_eq!(snapshot.stack, vec![Value::Int(3)]);
+
+    assert_eq!(evaluator.stack, vec![Value::Int(1), Value::Int(2)]);
+    assert_eq!(evaluator.get_var("x"), Some(&Value::Int(1)));
+    assert_eq!(evaluator.get_var("y"), None);
+
+    Ok(())
 }
 
 #[test]
-fn test1() -> Result<(), EngineError> {
-    let commands = vec![
-        Command::SetVar("a".into(), Value::Int(100)),
-        Command::GetVar("a".into()),
-    ];
+fn eval_count() -> Result<(), EngineError> {
+    let input = "push 1\npush 2\npush 1\npush \"1\"\npush 1\nmakelist 5\npush 1\ncount\npop";
 
-    let mut evaluator = Evaluator::new();
+    let commands = parse(input)?;
 
+    let mut evaluator = Evaluator::new();
     let result = evaluator.evaluate(&commands)?;
 
-    assert_eq!(result, Value::Int(100));
+    assert_eq!(result, Value::Int(3));
 
     Ok(())
 }
 
 #[test]
-fn eval_set_get() -> Result<(), EngineError> {
-    let input = "set x 30\nget x";
+fn eval_count_no_matches() -> Result<(), EngineError> {
+    let input = "push 1\npush 2\nmakelist 2\npush 3\ncount\npop";
 
     let commands = parse(input)?;
 
     let mut evaluator = Evaluator::new();
     let result = evaluator.evaluate(&commands)?;
 
-    assert_eq!(result, Value::Int(30));
+    assert_eq!(result, Value::Int(0));
 
     Ok(())
 }
 
 #[test]
-fn eval_set_get_string() -> Result<(), EngineError> {
-    let input = "set x \"hello\"\nget x";
+fn eval_count_not_list() -> Result<(), EngineError> {
+    let input = "push \"aaa\"\npush \"a\"\ncount\npop";
 
     let commands = parse(input)?;
 
     let mut evaluator = Evaluator::new();
-    let result = evaluator.evaluate(&commands)?;
+    let result = evaluator.evaluate(&commands);
 
-    assert_eq!(result, Value::String("hello".into()));
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::MismatchType)
+    ));
 
     Ok(())
 }
 
 #[test]
-fn eval_stack() -> Result<(), EngineError> {
-    let input = "push 100\npush 30\nadd\npop";
+fn eval_slice() -> Result<(), EngineError> {
+    let input = "push 1\npush 2\npush 3\npush 4\nmakelist 4\npush 1\npush 3\nslice\npop";
 
     let commands = parse(input)?;
 
     let mut evaluator = Evaluator::new();
     let result = evaluator.evaluate(&commands)?;
 
-    assert_eq!(result, Value::Int(130));
+    assert_eq!(
+        result,
+        Value::List(Rc::new(vec![Value::Int(2), Value::Int(3)]))
+    );
 
     Ok(())
 }
 
 #[test]
-fn eval_pushvar() -> Result<(), EngineError> {
-    let input = "set x 33\npushvar x\npush 100\nadd\npop";
+fn eval_slice_empty() -> Result<(), EngineError> {
+    let input = "push 1\npush 2\nmakelist 2\npush 2\npush 2\nslice\npop";
 
     let commands = parse(input)?;
 
     let mut evaluator = Evaluator::new();
     let result = evaluator.evaluate(&commands)?;
 
-    assert_eq!(result, Value::Int(133));
+    assert_eq!(result, Value::List(Rc::new(vec![])));
 
     Ok(())
 }
 
-fn main() -> Result<(), EngineError> {
-    for arg in std::env::args().skip(1) {
-        let contents = std::fs::read_to_string(arg).unwrap();
-        let mut engine = Evaluator::new();
-        let commands = parse(&contents)?;
-        let answer = engine.evaluate(&commands)?;
+#[test]
+fn eval_slice_out_of_range() -> Result<(), EngineError> {
+    for (start, end) in [(0, 3), (2, 1), (-1, 1)] {
+        let input = format!(
+            "push 1\npush 2\nmakelist 2\npush {}\npush {}\nslice\npop",
+            start, end
+        );
+
+        let commands = parse(&input)?;
+
+        let mut evaluator = Evaluator::new();
+        let result = evaluator.evaluate(&commands);
 
-        println!("{:?}", answer);
+        assert!(matches!(
+            result.map_err(EngineError::root),
+            Err(EngineError::IndexOutOfBounds)
+        ));
     }
 
     Ok(())
 }
+
+#[test]
+fn eval_two_dup_stack_limit() -> Result<(), EngineError> {
+    let input = "push 1\npush 2\npush 3\n2dup";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::with_limits(4);
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::StackOverflow)
+    ));
+    assert_eq!(
+        evaluator.stack,
+        vec![Value::Int(1), Value::Int(2), Value::Int(3)]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn eval_tuck_stack_limit() -> Result<(), EngineError> {
+    let input = "push 1\npush 2\ntuck";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::with_limits(2);
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::StackOverflow)
+    ));
+    assert_eq!(evaluator.stack, vec![Value::Int(1), Value::Int(2)]);
+
+    Ok(())
+}
+
+#[test]
+fn eval_format_keeps_operands_on_error() -> Result<(), EngineError> {
+    let input = "push 1\npush \"{0} and {1}\"\nformat 1";
+
+    let commands = parse(input)?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::BadFormat)
+    ));
+    assert_eq!(
+        evaluator.stack,
+        vec![Value::Int(1), Value::String("{0} and {1}".into())]
+    );
+
+    let commands = parse("push \"{0}\"\nformat 1")?;
+
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.evaluate(&commands);
+
+    assert!(matches!(
+        result.map_err(EngineError::root),
+        Err(EngineError::EmptyStack)
+    ));
+    assert_eq!(evaluator.stack, vec![Value::String("{0}".into())]);
+
+    Ok(())
+}
+
+fn main() {
+    let (options, sources) = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("error: {}", message);
+            std::process::exit(1);
+        }
+    };
+
+    if sources.is_empty() {
+        repl(&options);
+        return;
+    }
+
+    // Keep going after a failure so every program gets a chance to run
+    let mut failed = false;
+    for source in sources {
+        let result = match &source {
+            Source::File(name) => run_file(name, &options, &mut std::io::stdin()),
+            Source::Inline(program) => run_program(program, &options),
+        };
+        match result {
+            Ok(_) if options.check => println!("ok"),
+            Ok(answer) => println!("{}", format_answer(&answer, &options)),
+            Err(err) => {
+                eprintln!("error: {}", err);
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        std::process::exit(1);
+    }
+}